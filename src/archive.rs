@@ -0,0 +1,381 @@
+use crate::io::get_root;
+use bincode::{deserialize, serialize};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression, Crc};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// Packed asset archive
+//  "BHPK", version (u32 le), index length (u64 le), index (bincode), then every entry's data
+//  back to back. Entries are deflated when that makes them smaller and carry a crc32 of
+//  their unpacked contents, checked on every read.
+pub const ARCHIVE_FILE: &str = "assets.pak";
+const MAGIC: &[u8; 4] = b"BHPK";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 16;
+
+// the directories under the asset root that get packed
+const ASSET_DIRS: [&str; 4] = ["textures", "shaders", "audio", "levels"];
+
+#[derive(Debug)]
+pub enum ArchiveErr {
+    Io(PathBuf, io::Error),
+    NotAnArchive(PathBuf),
+    Index(PathBuf, bincode::Error),
+    Missing(String), // asset name
+    Corrupt(String), // asset name, the checksum didn't match
+}
+
+impl fmt::Display for ArchiveErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveErr::Io(path, e) => write!(f, "{:?}: {}", path, e),
+            ArchiveErr::NotAnArchive(path) => write!(f, "{:?} is not an asset archive", path),
+            ArchiveErr::Index(path, e) => write!(f, "{:?} has a bad index: {}", path, e),
+            ArchiveErr::Missing(name) => write!(f, "missing asset {:?}", name),
+            ArchiveErr::Corrupt(name) => write!(f, "asset {:?} is corrupt", name),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveErr {}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String, // relative to the asset root, with / separators
+    offset: u64,  // from the end of the index
+    size: u64,    // as stored
+    raw_size: u64,
+    compressed: bool,
+    crc: u32, // of the unpacked data
+}
+
+pub struct Archive {
+    path: PathBuf,
+    index: Vec<Entry>,
+    data_start: u64,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Archive, ArchiveErr> {
+        let io_err = |e| ArchiveErr::Io(path.to_path_buf(), e);
+        let mut file = File::open(path).map_err(io_err)?;
+        let file_len = file.metadata().map_err(io_err)?.len();
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut header)
+            .map_err(|_| ArchiveErr::NotAnArchive(path.to_path_buf()))?;
+        let version = u32_le(&header[4..8]);
+        let index_len = u64_le(&header[8..16]);
+        if &header[0..4] != MAGIC || version != VERSION || index_len > file_len - HEADER_LEN {
+            return Err(ArchiveErr::NotAnArchive(path.to_path_buf()));
+        }
+
+        let mut index = vec![0u8; index_len as usize];
+        file.read_exact(&mut index).map_err(io_err)?;
+        let index: Vec<Entry> =
+            deserialize(&index).map_err(|e| ArchiveErr::Index(path.to_path_buf(), e))?;
+        let data_start = HEADER_LEN + index_len;
+        let data_len = file_len - data_start;
+        let out_of_bounds = |entry: &Entry| match entry.offset.checked_add(entry.size) {
+            Some(end) => end > data_len,
+            None => true,
+        };
+        if index.iter().any(out_of_bounds) {
+            return Err(ArchiveErr::NotAnArchive(path.to_path_buf()));
+        }
+
+        Ok(Archive {
+            path: path.to_path_buf(),
+            index,
+            data_start,
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.index.iter().map(|entry| entry.name.as_str())
+    }
+
+    // the unpacked contents of an entry
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, ArchiveErr> {
+        let entry = self
+            .index
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| ArchiveErr::Missing(name.to_string()))?;
+        let io_err = |e| ArchiveErr::Io(self.path.clone(), e);
+
+        let mut file = File::open(&self.path).map_err(io_err)?;
+        file.seek(SeekFrom::Start(self.data_start + entry.offset))
+            .map_err(io_err)?;
+        let mut stored = vec![0u8; entry.size as usize];
+        file.read_exact(&mut stored).map_err(io_err)?;
+
+        let data = if entry.compressed {
+            let mut data = Vec::new();
+            DeflateDecoder::new(&stored[..])
+                .read_to_end(&mut data)
+                .map_err(|_| ArchiveErr::Corrupt(name.to_string()))?;
+            data
+        } else {
+            stored
+        };
+        if data.len() as u64 != entry.raw_size || crc32(&data) != entry.crc {
+            return Err(ArchiveErr::Corrupt(name.to_string()));
+        }
+        Ok(data)
+    }
+}
+
+// packs every file in the asset directories under dir into out, returns the entry count
+pub fn pack(dir: &Path, out: &Path) -> Result<usize, ArchiveErr> {
+    let mut files = Vec::new();
+    for asset_dir in ASSET_DIRS.iter() {
+        if dir.join(asset_dir).is_dir() {
+            collect_files(dir, Path::new(asset_dir), &mut files)?;
+        }
+    }
+    files.sort();
+
+    let mut index = Vec::new();
+    let mut data = Vec::new();
+    for name in files {
+        let path = dir.join(&name);
+        let raw = fs::read(&path).map_err(|e| ArchiveErr::Io(path.clone(), e))?;
+
+        // already compressed formats (png, ogg) usually don't shrink, so store those as is
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        let deflated = encoder
+            .write_all(&raw)
+            .and_then(|_| encoder.finish())
+            .map_err(|e| ArchiveErr::Io(path.clone(), e))?;
+        let compressed = deflated.len() < raw.len();
+        let crc = crc32(&raw);
+        let raw_size = raw.len() as u64;
+        let stored = if compressed { deflated } else { raw };
+
+        index.push(Entry {
+            name,
+            offset: data.len() as u64,
+            size: stored.len() as u64,
+            raw_size,
+            compressed,
+            crc,
+        });
+        data.extend_from_slice(&stored);
+    }
+
+    let index_bytes = serialize(&index).map_err(|e| ArchiveErr::Index(out.to_path_buf(), e))?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN as usize + index_bytes.len() + data.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(index_bytes.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&index_bytes);
+    bytes.extend_from_slice(&data);
+    fs::write(out, bytes).map_err(|e| ArchiveErr::Io(out.to_path_buf(), e))?;
+    Ok(index.len())
+}
+
+// every file below root/rel, as names relative to root
+fn collect_files(root: &Path, rel: &Path, files: &mut Vec<String>) -> Result<(), ArchiveErr> {
+    let dir = root.join(rel);
+    let entries = fs::read_dir(&dir).map_err(|e| ArchiveErr::Io(dir.clone(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| ArchiveErr::Io(dir.clone(), e))?;
+        let rel = rel.join(entry.file_name());
+        if entry.path().is_dir() {
+            collect_files(root, &rel, files)?;
+        } else {
+            let name: Vec<_> = rel.iter().map(|part| part.to_string_lossy()).collect();
+            files.push(name.join("/"));
+        }
+    }
+    Ok(())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+fn u64_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+// Where assets are read from: the binary if they were embedded (and no asset directory was
+// named), assets.pak in the asset root if there is one, loose files otherwise. Names are
+// relative to the root, eg. "textures/mastercomp.png".
+pub enum Assets {
+    Dir(PathBuf),
+    Archive(Archive),
+    #[cfg(feature = "embed-assets")]
+    Embedded,
+}
+
+impl Assets {
+    pub fn open() -> Result<Assets, ArchiveErr> {
+        #[cfg(feature = "embed-assets")]
+        {
+            if !crate::io::root_is_named() {
+                return Ok(Assets::Embedded);
+            }
+        }
+        let root = get_root();
+        let archive = root.join(ARCHIVE_FILE);
+        if archive.is_file() {
+            Ok(Assets::Archive(Archive::open(&archive)?))
+        } else {
+            Ok(Assets::Dir(root))
+        }
+    }
+
+    // the names of the files directly in dir (eg. "textures"), sorted
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let prefix = format!("{}/", dir);
+        let directly_in =
+            |name: &&str| name.starts_with(&prefix) && !name[prefix.len()..].contains('/');
+        let mut names: Vec<String> = match self {
+            Assets::Dir(root) => fs::read_dir(root.join(dir))
+                .map(|entries| {
+                    entries
+                        .filter_map(Result::ok)
+                        .filter(|entry| entry.path().is_file())
+                        .map(|entry| format!("{}{}", prefix, entry.file_name().to_string_lossy()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Assets::Archive(archive) => archive
+                .names()
+                .filter(directly_in)
+                .map(str::to_string)
+                .collect(),
+            #[cfg(feature = "embed-assets")]
+            Assets::Embedded => crate::embed::names()
+                .filter(directly_in)
+                .map(str::to_string)
+                .collect(),
+        };
+        names.sort();
+        names
+    }
+
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, ArchiveErr> {
+        match self {
+            Assets::Dir(root) => fs::read(root.join(name)).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => ArchiveErr::Missing(name.to_string()),
+                _ => ArchiveErr::Io(root.join(name), e),
+            }),
+            Assets::Archive(archive) => archive.read(name),
+            #[cfg(feature = "embed-assets")]
+            Assets::Embedded => crate::embed::get(name)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| ArchiveErr::Missing(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a small asset tree in a fresh directory under temp, and an archive of it in there
+    fn packed(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("blockhop_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let noise: Vec<u8> = (0..256u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let files: [(&str, &[u8]); 3] = [
+            ("shaders/sprite.vert", &b"void main() {}\n".repeat(20)),
+            ("textures/noise.bin", &noise),
+            ("levels/nested/deep.toml", b"[well]\n"),
+        ];
+        for (name, data) in files.iter() {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        let out = dir.join(ARCHIVE_FILE);
+        assert_eq!(pack(&dir, &out).unwrap(), files.len());
+        (dir, out)
+    }
+
+    #[test]
+    fn packed_assets_read_back() {
+        let (dir, out) = packed("archive_read");
+        let archive = Archive::open(&out).unwrap();
+        let names: Vec<String> = archive.names().map(str::to_string).collect();
+        assert_eq!(
+            names,
+            vec![
+                "levels/nested/deep.toml",
+                "shaders/sprite.vert",
+                "textures/noise.bin"
+            ]
+        );
+        for name in &names {
+            assert_eq!(
+                archive.read(name).unwrap(),
+                fs::read(dir.join(name)).unwrap()
+            );
+        }
+        // the shader shrinks, the noise doesn't
+        let compressed: Vec<bool> = archive.index.iter().map(|e| e.compressed).collect();
+        assert_eq!(compressed, vec![false, true, false]);
+        match archive.read("textures/missing.png") {
+            Err(ArchiveErr::Missing(_)) => {}
+            _ => panic!("read a missing asset"),
+        }
+
+        let assets = Assets::Archive(archive);
+        assert_eq!(assets.list("shaders"), vec!["shaders/sprite.vert"]);
+        assert!(assets.list("levels").is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn corrupt_entry_is_rejected() {
+        let (dir, out) = packed("archive_corrupt");
+        let archive = Archive::open(&out).unwrap();
+        let entry = archive
+            .index
+            .iter()
+            .find(|entry| entry.name == "textures/noise.bin")
+            .unwrap();
+        let mut bytes = fs::read(&out).unwrap();
+        bytes[(archive.data_start + entry.offset) as usize + 10] ^= 0xff;
+        fs::write(&out, bytes).unwrap();
+
+        let archive = Archive::open(&out).unwrap();
+        match archive.read("textures/noise.bin") {
+            Err(ArchiveErr::Corrupt(name)) => assert_eq!(name, "textures/noise.bin"),
+            _ => panic!("read a corrupt asset"),
+        }
+        assert!(archive.read("shaders/sprite.vert").is_ok());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn other_files_are_not_archives() {
+        let (dir, out) = packed("archive_other");
+        fs::write(&out, b"BHPK but not really").unwrap();
+        match Archive::open(&out) {
+            Err(ArchiveErr::NotAnArchive(_)) => {}
+            _ => panic!("opened something that isn't an archive"),
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,449 @@
+use crate::{
+    archive::Assets,
+    music::{MusicPlayer, Track},
+    settings::Volume,
+    time::get_microseconds_as_u64,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs::File, io::Cursor, path::Path, time::Duration};
+
+// The one-shot sound effects the update thread can trigger
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Sound {
+    BlockSpawn,
+    BlockLand,
+    BlockHit,
+    BlockDestroyed,
+    GameOver,
+}
+
+// Messages sent from the update thread to the audio thread
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum AudioEvent {
+    Play(Sound, f32), // sound, volume (0 to 1)
+    Music(Track),
+    Intensity(f32), // how high the stack is, 0 to 1
+    SetVolume(Volume),
+}
+
+#[derive(Debug)]
+pub enum AudioErr {
+    Write(hound::Error),
+}
+
+impl fmt::Display for AudioErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioErr::Write(e) => write!(f, "could not write audio output: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AudioErr {}
+
+// file stem, base volume, pitch variation (in semitones)
+fn sound_params(sound: Sound) -> (&'static str, f32, f32) {
+    match sound {
+        Sound::BlockSpawn => ("block_spawn", 0.4, 2.),
+        Sound::BlockLand => ("block_land", 0.8, 3.),
+        Sound::BlockHit => ("block_hit", 0.6, 2.),
+        Sound::BlockDestroyed => ("block_destroyed", 0.7, 3.),
+        Sound::GameOver => ("game_over", 1.0, 0.),
+    }
+}
+
+const ALL_SOUNDS: [Sound; 5] = [
+    Sound::BlockSpawn,
+    Sound::BlockLand,
+    Sound::BlockHit,
+    Sound::BlockDestroyed,
+    Sound::GameOver,
+];
+
+// A decoded sample, downmixed to mono
+pub struct Sample {
+    pub data: Vec<f32>,
+    pub rate: u32,
+}
+
+impl Sample {
+    // decodes the contents of a wav or ogg file
+    pub fn decode(data: Vec<u8>) -> Option<Sample> {
+        use rodio::Source;
+
+        let decoder = rodio::Decoder::new(Cursor::new(data)).ok()?;
+        let channels = decoder.channels() as usize;
+        let rate = decoder.sample_rate();
+        let interleaved: Vec<i16> = decoder.collect();
+        let data = interleaved
+            .chunks(channels)
+            .map(|frame| frame.iter().map(|&s| s as f32 / 32768.).sum::<f32>() / channels as f32)
+            .collect();
+        Some(Sample { data, rate })
+    }
+}
+
+// A sample currently being played
+struct Voice {
+    sound: Sound,
+    pos: f64,  // in source samples
+    step: f64, // source samples per output sample, includes pitch
+    gain: f32,
+}
+
+// Sums all playing voices into an output buffer
+pub struct Mixer {
+    rate: u32,
+    samples: HashMap<Sound, Sample>,
+    voices: Vec<Voice>,
+    pub volume: f32,
+}
+
+impl Mixer {
+    const MAX_VOICES: usize = 32;
+
+    pub fn new(rate: u32, samples: HashMap<Sound, Sample>) -> Mixer {
+        Mixer {
+            rate,
+            samples,
+            voices: Vec::new(),
+            volume: 1.,
+        }
+    }
+
+    // starts a new voice, randomizing its pitch and volume a little
+    pub fn play(&mut self, sound: Sound, volume: f32) {
+        let sample = match self.samples.get(&sound) {
+            Some(s) => s,
+            None => return,
+        };
+        let (_, base_volume, pitch_variation) = sound_params(sound);
+
+        let mut rng = rand::thread_rng();
+        let semitones = if pitch_variation > 0. {
+            rng.gen_range(-pitch_variation, pitch_variation)
+        } else {
+            0.
+        };
+        let pitch = 2f64.powf(semitones as f64 / 12.);
+        let gain = base_volume * volume * rng.gen_range(0.9, 1.0);
+
+        // steal the oldest voice if we're out
+        if self.voices.len() >= Self::MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push(Voice {
+            sound,
+            pos: 0.,
+            step: pitch * sample.rate as f64 / self.rate as f64,
+            gain,
+        });
+    }
+
+    pub fn mix(&mut self, out: &mut [f32]) {
+        for s in out.iter_mut() {
+            *s = 0.;
+        }
+
+        let samples = &self.samples;
+        let volume = self.volume;
+        for voice in self.voices.iter_mut() {
+            let data = &samples[&voice.sound].data;
+            for s in out.iter_mut() {
+                let i = voice.pos as usize;
+                if i + 1 >= data.len() {
+                    break;
+                }
+
+                // linear interpolation between neighbouring source samples
+                let t = (voice.pos - i as f64) as f32;
+                *s += (data[i] * (1. - t) + data[i + 1] * t) * voice.gain * volume;
+                voice.pos += voice.step;
+            }
+        }
+
+        // drop finished voices
+        self.voices
+            .retain(|voice| voice.pos as usize + 1 < samples[&voice.sound].data.len());
+    }
+}
+
+// Where the mixed audio ends up
+pub trait AudioBackend {
+    fn sample_rate(&self) -> u32;
+    fn write(&mut self, samples: &[f32]) -> Result<(), AudioErr>;
+}
+
+// Plays through the default output device
+pub struct DeviceBackend {
+    sink: rodio::Sink,
+    rate: u32,
+}
+
+impl DeviceBackend {
+    pub fn new() -> Option<DeviceBackend> {
+        let device = rodio::default_output_device()?;
+        Some(DeviceBackend {
+            sink: rodio::Sink::new(&device),
+            rate: 44_100,
+        })
+    }
+}
+
+impl AudioBackend for DeviceBackend {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), AudioErr> {
+        self.sink.append(rodio::buffer::SamplesBuffer::new(
+            1,
+            self.rate,
+            samples.to_vec(),
+        ));
+        Ok(())
+    }
+}
+
+// Discards everything, for machines without a sound device
+pub struct NullBackend;
+
+impl AudioBackend for NullBackend {
+    fn sample_rate(&self) -> u32 {
+        22_050
+    }
+
+    fn write(&mut self, _: &[f32]) -> Result<(), AudioErr> {
+        Ok(())
+    }
+}
+
+// Records everything to a 16 bit mono wav file
+pub struct FileBackend {
+    writer: hound::WavWriter<std::io::BufWriter<File>>,
+    rate: u32,
+}
+
+impl FileBackend {
+    pub fn new(path: &Path) -> Option<FileBackend> {
+        let rate = 44_100;
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer = hound::WavWriter::create(path, spec).ok()?;
+        Some(FileBackend { writer, rate })
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), AudioErr> {
+        for s in samples {
+            self.writer
+                .write_sample((s * 32767.) as i16)
+                .map_err(AudioErr::Write)?;
+        }
+        Ok(())
+    }
+}
+
+// Picks a backend from BLOCKHOP_AUDIO: "null", "device" (default) or a path to a .wav file
+pub fn open_backend() -> Box<dyn AudioBackend> {
+    let choice = std::env::var("BLOCKHOP_AUDIO").unwrap_or_default();
+    match choice.as_str() {
+        "null" => Box::new(NullBackend),
+        "" | "device" => match DeviceBackend::new() {
+            Some(b) => Box::new(b),
+            None => {
+                println!("No audio device found, audio disabled");
+                Box::new(NullBackend)
+            }
+        },
+        path => match FileBackend::new(Path::new(path)) {
+            Some(b) => Box::new(b),
+            None => {
+                println!("Could not open {:?} for audio output, audio disabled", path);
+                Box::new(NullBackend)
+            }
+        },
+    }
+}
+
+// loads audio/sfx/<name>.wav or .ogg for every sound
+fn load_samples() -> HashMap<Sound, Sample> {
+    let mut samples = HashMap::new();
+    let assets = match Assets::open() {
+        Ok(assets) => assets,
+        Err(e) => {
+            println!("Could not load sounds: {}", e);
+            return samples;
+        }
+    };
+    for &sound in ALL_SOUNDS.iter() {
+        let (stem, _, _) = sound_params(sound);
+        let sample = ["wav", "ogg"]
+            .iter()
+            .filter_map(|ext| assets.read(&format!("audio/sfx/{}.{}", stem, ext)).ok())
+            .filter_map(Sample::decode)
+            .next();
+        match sample {
+            Some(sample) => {
+                println!("Loaded sound: {:?} as {:?}", stem, sound);
+                samples.insert(sound, sample);
+            }
+            None => println!("Missing sound: audio/sfx/{}", stem),
+        }
+    }
+    samples
+}
+
+// The mixer and music player, feeding a backend in blocks of 10ms
+struct Output {
+    backend: Box<dyn AudioBackend>,
+    mixer: Mixer,
+    music: MusicPlayer,
+    block: Vec<f32>,
+}
+
+impl Output {
+    fn new(backend: Box<dyn AudioBackend>, samples: HashMap<Sound, Sample>) -> Output {
+        let rate = backend.sample_rate();
+        Output {
+            backend,
+            mixer: Mixer::new(rate, samples),
+            music: MusicPlayer::new(rate),
+            block: vec![0.; rate as usize / 100],
+        }
+    }
+
+    fn handle(&mut self, event: AudioEvent) {
+        match event {
+            AudioEvent::Play(sound, volume) => self.mixer.play(sound, volume),
+            AudioEvent::Music(track) => self.music.play(track),
+            AudioEvent::Intensity(intensity) => self.music.set_intensity(intensity),
+            AudioEvent::SetVolume(volume) => {
+                self.mixer.volume = volume.master * volume.sfx;
+                self.music.volume = volume.master * volume.music;
+            }
+        }
+    }
+
+    // mixes and writes the next block, returns how many samples were written
+    fn write_block(&mut self) -> Result<u64, AudioErr> {
+        self.mixer.mix(&mut self.block);
+        self.music.mix(&mut self.block);
+        for s in self.block.iter_mut() {
+            *s = nalgebra::clamp(*s, -1., 1.);
+        }
+        self.backend.write(&self.block)?;
+        Ok(self.block.len() as u64)
+    }
+}
+
+pub fn audio(
+    backend: Box<dyn AudioBackend>,
+    volume: Volume,
+    audio_recv: Receiver<AudioEvent>,
+) -> Result<(), AudioErr> {
+    let rate = backend.sample_rate();
+    let mut output = Output::new(backend, load_samples());
+    output.handle(AudioEvent::SetVolume(volume));
+
+    // audio loop
+    //  Keeps roughly 50ms of audio queued ahead of the wall clock. The loop ends when the
+    //  update thread drops its sender.
+    let lead = rate as u64 / 20;
+    let start = get_microseconds_as_u64();
+    let mut written = 0u64;
+    loop {
+        match audio_recv.recv_timeout(Duration::from_millis(5)) {
+            Ok(event) => output.handle(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let elapsed = get_microseconds_as_u64() - start;
+        let due = elapsed * rate as u64 / 1_000_000 + lead;
+        while written < due {
+            written += output.write_block()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // half a second of a 440Hz sine for every sound
+    fn sine_samples() -> HashMap<Sound, Sample> {
+        let rate = 22_050;
+        let data: Vec<f32> = (0..rate / 2)
+            .map(|i| 0.5 * (i as f32 * 440. * 2. * std::f32::consts::PI / rate as f32).sin())
+            .collect();
+        ALL_SOUNDS
+            .iter()
+            .map(|&sound| {
+                let data = data.clone();
+                (sound, Sample { data, rate })
+            })
+            .collect()
+    }
+
+    // mixes a second of audio for the events into a wav file, returns what was written
+    fn record(name: &str, events: &[AudioEvent]) -> Vec<i16> {
+        let path =
+            std::env::temp_dir().join(format!("blockhop_{}_{}.wav", name, std::process::id()));
+        let backend = FileBackend::new(&path).expect("could not create a wav file");
+        let mut output = Output::new(Box::new(backend), sine_samples());
+        for &event in events {
+            output.handle(event);
+        }
+        let mut written = 0;
+        while written < 44_100 {
+            written += output.write_block().unwrap();
+        }
+        // the writer is finalized when the backend is dropped
+        drop(output);
+
+        let samples = hound::WavReader::open(&path)
+            .expect("could not read the wav file back")
+            .samples::<i16>()
+            .map(Result::unwrap)
+            .collect();
+        let _ = std::fs::remove_file(&path);
+        samples
+    }
+
+    fn peak(samples: &[i16]) -> i16 {
+        samples
+            .iter()
+            .map(|s| s.saturating_abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn played_sounds_are_written() {
+        let samples = record("played", &[AudioEvent::Play(Sound::BlockLand, 1.)]);
+        assert_eq!(samples.len(), 44_100);
+        // sine peak * sound volume, less a little for the random gain
+        assert!(peak(&samples) > 8_000, "peak {}", peak(&samples));
+        // the sound is half a second long, the rest is silence
+        assert_eq!(peak(&samples[33_075..]), 0);
+    }
+
+    #[test]
+    fn muted_sounds_are_silent() {
+        let samples = record("muted", &[AudioEvent::Play(Sound::BlockLand, 0.)]);
+        assert_eq!(samples.len(), 44_100);
+        assert_eq!(peak(&samples), 0);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::components::BlockKind;
+use std::fmt;
+
+// The well as a grid
+//  Resting blocks are snapped into a grid of the 7 spawn columns (the same cells the cursor
+//  snaps to), rows counted up from the floor, and rebuilt after every physics step. Falling
+//  blocks aren't on the board. A block resting crooked, or off its cell's center, is still put
+//  in the nearest cell so it counts towards heights, but isn't aligned, so it can't match.
+pub const COLUMNS: usize = 7;
+const LEFT: f32 = 80.; // center of the first column
+const CELL: f32 = 32.;
+const TOLERANCE: f32 = 8.; // how far off a cell center a block may rest and still be aligned
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub kind: BlockKind,
+    pub aligned: bool,
+}
+
+pub struct Board {
+    floor: f32, // center of the bottom row
+    rows: Vec<[Option<Cell>; COLUMNS]>,
+}
+
+impl Board {
+    // floor is the y of the well floor
+    pub fn new(floor: f32) -> Board {
+        Board {
+            floor: floor - CELL / 2.,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+    }
+
+    // the (column, row) a block centered at xy is nearest, and whether it's aligned with it
+    //  Something resting on a wall goes in the column beside it, None is below the floor.
+    pub fn cell_at(&self, xy: (f32, f32)) -> Option<((usize, usize), bool)> {
+        let column = ((xy.0 - LEFT) / CELL).round();
+        let row = ((self.floor - xy.1) / CELL).round();
+        if row < 0. {
+            return None;
+        }
+        let column = nalgebra::clamp(column, 0., COLUMNS as f32 - 1.);
+        let center = (LEFT + column * CELL, self.floor - row * CELL);
+        let aligned = (xy.0 - center.0).abs() <= TOLERANCE && (xy.1 - center.1).abs() <= TOLERANCE;
+        Some(((column as usize, row as usize), aligned))
+    }
+
+    // an aligned block wins a cell over one that isn't
+    pub fn insert(&mut self, cell: (usize, usize), contents: Cell) {
+        let (column, row) = cell;
+        if self.rows.len() <= row {
+            self.rows.resize(row + 1, [None; COLUMNS]);
+        }
+        let old = &mut self.rows[row][column];
+        if contents.aligned || !old.map(|old| old.aligned).unwrap_or(false) {
+            *old = Some(contents);
+        }
+    }
+
+    pub fn get(&self, cell: (usize, usize)) -> Option<Cell> {
+        let (column, row) = cell;
+        self.rows.get(row).and_then(|cells| cells[column])
+    }
+
+    // how many cells high each column is stacked, counting gaps below its top block
+    pub fn heights(&self) -> [u32; COLUMNS] {
+        let mut heights = [0; COLUMNS];
+        for (row, cells) in self.rows.iter().enumerate() {
+            for (column, cell) in cells.iter().enumerate() {
+                if cell.is_some() {
+                    heights[column] = row as u32 + 1;
+                }
+            }
+        }
+        heights
+    }
+
+    // every cell in a group of at least match_size touching aligned blocks of one color
+    pub fn matches(&self, match_size: usize) -> Vec<(usize, usize)> {
+        let color = |cell: (usize, usize)| match self.get(cell) {
+            Some(Cell {
+                kind: BlockKind::Colored(color),
+                aligned: true,
+            }) => Some(color),
+            _ => None,
+        };
+        let mut seen = vec![[false; COLUMNS]; self.rows.len()];
+        let mut matched = Vec::new();
+        for row in 0..self.rows.len() {
+            for column in 0..COLUMNS {
+                let group_color = match color((column, row)) {
+                    Some(c) if !seen[row][column] => c,
+                    _ => continue,
+                };
+
+                // flood fill the group this cell is in
+                let mut group = Vec::new();
+                let mut open = vec![(column, row)];
+                seen[row][column] = true;
+                while let Some((c, r)) = open.pop() {
+                    group.push((c, r));
+                    let mut neighbours = vec![(c + 1, r), (c, r + 1)];
+                    if c > 0 {
+                        neighbours.push((c - 1, r));
+                    }
+                    if r > 0 {
+                        neighbours.push((c, r - 1));
+                    }
+                    for (nc, nr) in neighbours {
+                        let inside = nc < COLUMNS && nr < self.rows.len();
+                        if inside && !seen[nr][nc] && color((nc, nr)) == Some(group_color) {
+                            seen[nr][nc] = true;
+                            open.push((nc, nr));
+                        }
+                    }
+                }
+
+                if group.len() >= match_size {
+                    matched.extend(group);
+                }
+            }
+        }
+        matched
+    }
+}
+
+// top row first, eg.
+//  |...*...|
+//  |..#0%..|
+//  +-------+
+// # normal, * explosive, % garbage, 0-3 colors, lowercase letters for crooked blocks
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cells in self.rows.iter().rev() {
+            write!(f, "|")?;
+            for cell in cells.iter() {
+                let c = match cell {
+                    None => '.',
+                    Some(Cell { kind, aligned }) => match (kind, aligned) {
+                        (BlockKind::Normal, true) => '#',
+                        (BlockKind::Explosive, true) => '*',
+                        (BlockKind::Garbage, true) => '%',
+                        (BlockKind::Colored(color), true) => (b'0' + color) as char,
+                        (BlockKind::Normal, false) => 'n',
+                        (BlockKind::Explosive, false) => 'x',
+                        (BlockKind::Garbage, false) => 'g',
+                        (BlockKind::Colored(color), false) => (b'a' + color) as char,
+                    },
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f, "|")?;
+        }
+        write!(f, "+{}+", "-".repeat(COLUMNS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLOOR: f32 = 480.;
+
+    fn cell(kind: BlockKind, aligned: bool) -> Cell {
+        Cell { kind, aligned }
+    }
+
+    // the center of a cell, offset by (dx, dy)
+    fn xy(column: usize, row: usize, dx: f32, dy: f32) -> (f32, f32) {
+        (
+            LEFT + column as f32 * CELL + dx,
+            FLOOR - CELL / 2. - row as f32 * CELL + dy,
+        )
+    }
+
+    #[test]
+    fn cell_at_snaps_to_the_nearest_cell() {
+        let board = Board::new(FLOOR);
+        assert_eq!(board.cell_at(xy(0, 0, 0., 0.)), Some(((0, 0), true)));
+        assert_eq!(board.cell_at(xy(3, 2, 6., -6.)), Some(((3, 2), true)));
+        assert_eq!(board.cell_at(xy(3, 2, 12., 0.)), Some(((3, 2), false)));
+        assert_eq!(board.cell_at(xy(4, 1, -14., 0.)), Some(((4, 1), false)));
+        // walls are beside the first and last columns
+        assert_eq!(board.cell_at(xy(0, 1, -CELL, 0.)), Some(((0, 1), false)));
+        assert_eq!(
+            board.cell_at(xy(COLUMNS - 1, 1, CELL, 0.)),
+            Some(((COLUMNS - 1, 1), false))
+        );
+        // below the floor
+        assert_eq!(board.cell_at(xy(2, 0, 0., CELL)), None);
+    }
+
+    #[test]
+    fn insert_prefers_aligned_blocks() {
+        let mut board = Board::new(FLOOR);
+        assert_eq!(board.to_string(), "+-------+");
+
+        board.insert((1, 0), cell(BlockKind::Normal, false));
+        board.insert((1, 0), cell(BlockKind::Explosive, true));
+        board.insert((1, 0), cell(BlockKind::Garbage, false));
+        board.insert((4, 2), cell(BlockKind::Colored(2), false));
+        board.insert((6, 0), cell(BlockKind::Garbage, true));
+        assert_eq!(board.get((1, 0)), Some(cell(BlockKind::Explosive, true)));
+        assert_eq!(board.get((0, 5)), None);
+        assert_eq!(
+            board.to_string(),
+            "|....c..|\n\
+             |.......|\n\
+             |.*....%|\n\
+             +-------+"
+        );
+
+        board.clear();
+        assert_eq!(board.to_string(), "+-------+");
+    }
+
+    #[test]
+    fn heights_count_gaps_below_the_top() {
+        let mut board = Board::new(FLOOR);
+        board.insert((0, 0), cell(BlockKind::Normal, true));
+        board.insert((2, 3), cell(BlockKind::Normal, false));
+        board.insert((5, 0), cell(BlockKind::Colored(0), true));
+        board.insert((5, 1), cell(BlockKind::Colored(1), true));
+        assert_eq!(
+            board.to_string(),
+            "|..n....|\n\
+             |.......|\n\
+             |.....1.|\n\
+             |#....0.|\n\
+             +-------+"
+        );
+        assert_eq!(board.heights(), [1, 0, 4, 0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn matches_touching_aligned_blocks_of_one_color() {
+        let mut board = Board::new(FLOOR);
+        let cells = [
+            ((0, 0), BlockKind::Colored(0), true),
+            ((1, 0), BlockKind::Colored(0), true),
+            ((1, 1), BlockKind::Colored(0), true),
+            ((2, 0), BlockKind::Colored(1), true),
+            ((3, 0), BlockKind::Colored(1), true),
+            ((3, 1), BlockKind::Colored(1), false),
+            ((4, 0), BlockKind::Colored(1), true),
+            ((5, 0), BlockKind::Normal, true),
+            ((6, 0), BlockKind::Colored(2), true),
+            ((6, 1), BlockKind::Colored(2), true),
+            ((6, 2), BlockKind::Colored(2), true),
+        ];
+        for &(at, kind, aligned) in cells.iter() {
+            board.insert(at, cell(kind, aligned));
+        }
+        assert_eq!(
+            board.to_string(),
+            "|......2|\n\
+             |.0.b..2|\n\
+             |00111#2|\n\
+             +-------+"
+        );
+
+        let mut matched = board.matches(3);
+        matched.sort();
+        assert_eq!(
+            matched,
+            vec![
+                (0, 0),
+                (1, 0),
+                (1, 1),
+                (2, 0),
+                (3, 0),
+                (4, 0),
+                (6, 0),
+                (6, 1),
+                (6, 2)
+            ]
+        );
+        // the crooked block doesn't join in, leaving the 1s a group of three
+        assert!(board.matches(4).is_empty());
+    }
+}
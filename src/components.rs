@@ -1,13 +1,23 @@
+use crate::{
+    fracture::Piece,
+    settings::{Material, Materials},
+};
 use compy::compy::*;
-use nalgebra::Vector2;
-use ncollide2d::shape::{Cuboid, ShapeHandle};
+use nalgebra::{Point2, Vector2};
+use ncollide2d::{
+    pipeline::object::CollisionGroups,
+    shape::{Ball, Compound, ConvexPolygon, Cuboid, ShapeHandle},
+};
 use nphysics2d::{
-    math::Velocity,
+    material::{BasicMaterial, MaterialHandle},
+    math::{Isometry, Velocity},
     object::{
         BodyPartHandle, BodyStatus, ColliderDesc, DefaultBodyHandle, DefaultBodySet,
         DefaultColliderHandle, DefaultColliderSet, RigidBodyDesc,
     },
 };
+use serde_derive::Deserialize;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 // The properties of the final sprite to be rendered
 #[derive(Copy, Clone)]
@@ -18,6 +28,19 @@ pub struct SpriteUV(pub f32, pub f32);
 pub struct SpriteWH(pub f32, pub f32);
 #[derive(Copy, Clone)]
 pub struct SpriteR(pub f32, pub f32, pub f32); // r, cx, cy
+#[derive(Copy, Clone)]
+pub struct SpriteId(pub u32); // lets the renderer match sprites between render states
+
+// unique, nonzero, sprite ids
+fn next_sprite_id() -> SpriteId {
+    next_sprite_ids(1)
+}
+
+// the first of count ids in a row, for an entity drawn with more than one sprite
+fn next_sprite_ids(count: u32) -> SpriteId {
+    static NEXT: AtomicU32 = AtomicU32::new(1);
+    SpriteId(NEXT.fetch_add(count, Ordering::Relaxed))
+}
 
 // Some physics stuff
 #[derive(Copy, Clone)]
@@ -29,17 +52,173 @@ pub struct PhysicsCollider(pub DefaultColliderHandle);
 #[derive(Copy, Clone)]
 pub struct SyncSpriteToPhysics;
 
+// Collision groups, each collider is a member of one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Group {
+    Walls,
+    Blocks,
+    Particles,
+    Sensors, // triggers, they report contacts but don't push back
+    Cursor,
+    Hopper,
+}
+
+impl Group {
+    // the groups this one collides with, kept symmetric
+    fn touches(self) -> &'static [usize] {
+        const WALLS: usize = Group::Walls as usize;
+        const BLOCKS: usize = Group::Blocks as usize;
+        const PARTICLES: usize = Group::Particles as usize;
+        const SENSORS: usize = Group::Sensors as usize;
+        const CURSOR: usize = Group::Cursor as usize;
+        const HOPPER: usize = Group::Hopper as usize;
+        match self {
+            Group::Walls => &[BLOCKS, PARTICLES, HOPPER],
+            Group::Blocks => &[WALLS, BLOCKS, SENSORS, CURSOR, HOPPER],
+            Group::Particles => &[WALLS],
+            Group::Sensors => &[BLOCKS],
+            Group::Cursor => &[BLOCKS],
+            Group::Hopper => &[WALLS, BLOCKS],
+        }
+    }
+
+    pub fn collision_groups(self) -> CollisionGroups {
+        CollisionGroups::new()
+            .with_membership(&[self as usize])
+            .with_whitelist(self.touches())
+    }
+
+    pub fn is_member(self, groups: &CollisionGroups) -> bool {
+        groups.is_member_of(self as usize)
+    }
+}
+
+// Puzzle mode block colors, an index into BLOCK_COLOR_UVS
+#[derive(Copy, Clone)]
+pub struct BlockColor(pub u8);
+
+pub const BLOCK_COLOR_UVS: [(f32, f32); 4] =
+    [(416., 144.), (544., 144.), (576., 144.), (704., 144.)];
+
+// The kinds of block there are, named like this in level scripts
+//  Every block (and fragment) entity has its kind as a component.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockKind {
+    Normal,
+    Explosive,
+    Garbage,
+    Colored(u8),
+}
+
+impl BlockKind {
+    // the block's texture in mastercomp.png
+    pub fn uv(self) -> (f32, f32) {
+        match self {
+            BlockKind::Normal => (352., 144.),
+            BlockKind::Explosive => (640., 144.),
+            BlockKind::Garbage => (448., 144.),
+            BlockKind::Colored(color) => BLOCK_COLOR_UVS[color as usize],
+        }
+    }
+}
+
+// The shape of a block's collider, named like this in level files
+//  Blocks made of more than one 32x32 cell are drawn with a sprite for each, with the body's
+//  origin at the center of the first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockShape {
+    Square,
+    Circle,
+    Triangle, // pointing up
+    L,
+    T,
+    S,
+}
+
+impl BlockShape {
+    // the centers of the cells the block covers, relative to its origin
+    pub fn cells(self) -> &'static [(f32, f32)] {
+        match self {
+            BlockShape::Square | BlockShape::Circle | BlockShape::Triangle => &[(0., 0.)],
+            BlockShape::L => &[(0., 0.), (0., -32.), (0., 32.), (32., 32.)],
+            BlockShape::T => &[(0., 0.), (-32., 0.), (32., 0.), (0., 32.)],
+            BlockShape::S => &[(0., 0.), (32., 0.), (-32., 32.), (0., 32.)],
+        }
+    }
+
+    // how many columns the block reaches (left, right) of the one its origin is in
+    pub fn reach(self) -> (usize, usize) {
+        self.cells().iter().fold((0, 0), |(left, right), &(x, _)| {
+            let column = (x / 32.) as i32;
+            (
+                left.max((-column).max(0) as usize),
+                right.max(column.max(0) as usize),
+            )
+        })
+    }
+
+    pub fn shape_handle(self) -> ShapeHandle<f32> {
+        // a little narrower than a cell, so blocks slide down between each other
+        let cell = || ShapeHandle::new(Cuboid::new(Vector2::new(15., 16.)));
+        match self {
+            BlockShape::Square => cell(),
+            BlockShape::Circle => ShapeHandle::new(Ball::new(16.)),
+            BlockShape::Triangle => {
+                let points = [
+                    Point2::new(-15., 16.),
+                    Point2::new(15., 16.),
+                    Point2::new(0., -16.),
+                ];
+                ShapeHandle::new(ConvexPolygon::try_from_points(&points).unwrap())
+            }
+            BlockShape::L | BlockShape::T | BlockShape::S => {
+                let cells = self
+                    .cells()
+                    .iter()
+                    .map(|&(x, y)| (Isometry::translation(x, y), cell()));
+                ShapeHandle::new(Compound::new(cells.collect()))
+            }
+        }
+    }
+}
+
+// A piece of a destroyed block, breaking up further once it's destroyed too
+#[derive(Copy, Clone)]
+pub struct Fragment {
+    pub kind: BlockKind,
+    pub wh: (f32, f32),
+}
+
 // HP and damage tags
 #[derive(Copy, Clone)]
 pub struct HP(pub u8); // no max
 #[derive(Copy, Clone)]
 pub struct TakeCursorDamage; // use PhysicsCollider(maybe a new collider?)
 
+// Blows up once its HP reaches 0, pushing away and damaging whatever is in radius
+#[derive(Copy, Clone)]
+pub struct Explosive {
+    pub radius: f32,
+    pub damage: u8,   // at the center, falling off to nothing at the radius
+    pub impulse: f32, // velocity change at the center
+}
+
+// The keyboard controlled character
+#[derive(Copy, Clone)]
+pub struct Hopper {
+    pub anim: f32,      // seconds into the current animation
+    pub wall_jump: f32, // seconds left of the last wall jump, when running can't steer
+}
+
 // kill on tags
 /*#[derive(Copy, Clone)]
 pub struct KillUponLeavingScreen; // Uses PhysicsBody for position*/
 #[derive(Copy, Clone)]
 pub struct KillUpon0HP;
+#[derive(Copy, Clone)]
+pub struct KillAfter(pub f32); // seconds left
 
 // UV setting, based on mouse holding state, pretty straightforward
 #[derive(Copy, Clone)]
@@ -51,7 +230,9 @@ pub struct SetUVOnLMBDown(pub f32, pub f32);
 #[derive(Copy, Clone)]
 pub struct CursorSnapSpriteToGrid; // snaps to the play area grid
 #[derive(Copy, Clone)]
-pub struct CursorEmitDestroyEventOnLMBDown; // emits a "destroy event" at the cursor location
+pub struct CursorEmitDestroyEventOnLMBDown; // emits a "destroy event" at the cursor, on a click
+#[derive(Copy, Clone)]
+pub struct CursorToolIcon; // shows the selected tool in the middle of the cursor
 
 // creates an unmoving, uncollidable, sprite with with xy being the top left corner
 pub fn create_sprite(xy: (f32, f32), uv: (f32, f32), wh: (f32, f32), compy: &Compy) {
@@ -60,87 +241,338 @@ pub fn create_sprite(xy: (f32, f32), uv: (f32, f32), wh: (f32, f32), compy: &Com
         SpriteUV(uv.0, uv.1),
         SpriteWH(wh.0, wh.1),
         SpriteR(0., 0., 0.),
+        next_sprite_id(),
     ));
 }
 
+// a collider of the given shape, made of material
+fn collider_desc(shape: ShapeHandle<f32>, material: &Material) -> ColliderDesc<f32> {
+    ColliderDesc::new(shape)
+        .material(MaterialHandle::new(BasicMaterial::new(
+            material.restitution,
+            material.friction,
+        )))
+        .density(material.density)
+}
+
 // creates an unmoving, solid, region with with xy being the top left corner
 pub fn create_wall(
     xy: (f32, f32),
     wh: (f32, f32),
+    material: &Material,
     compy: &Compy,
     world: DefaultBodyHandle,
     colliders: &mut DefaultColliderSet<f32>,
 ) {
-    let collider = ColliderDesc::new(ShapeHandle::new(Cuboid::new(Vector2::new(
-        wh.0 / 2.,
-        wh.1 / 2.,
-    ))))
-    .translation(Vector2::new(xy.0 + wh.0 / 2., xy.1 + wh.1 / 2.))
-    .build(BodyPartHandle(world, 0));
+    let shape = ShapeHandle::new(Cuboid::new(Vector2::new(wh.0 / 2., wh.1 / 2.)));
+    let collider = collider_desc(shape, material)
+        .translation(Vector2::new(xy.0 + wh.0 / 2., xy.1 + wh.1 / 2.))
+        .collision_groups(Group::Walls.collision_groups())
+        .build(BodyPartHandle(world, 0));
     let collider_handle = colliders.insert(collider);
     compy.insert((PhysicsCollider(collider_handle),));
 }
 
-pub fn create_normal_block(
+// the body and collider shared by all blocks, xy being the center of the first cell
+//  The body's mass is block.mass plus what the collider adds from its material's density.
+fn create_block_body(
     xy: (f32, f32),
-    compy: &Compy,
+    shape: BlockShape,
+    block: &crate::settings::Block,
+    material: &Material,
     bodies: &mut DefaultBodySet<f32>,
     colliders: &mut DefaultColliderSet<f32>,
-) {
+) -> (DefaultBodyHandle, DefaultColliderHandle) {
     let rigid_body = RigidBodyDesc::new()
         .translation(Vector2::new(xy.0, xy.1))
-        .velocity(Velocity::linear(0.0, 32.0))
+        .velocity(Velocity::linear(0.0, block.velocity))
         //.max_linear_velocity(64.0)
-        .mass(0.02)
+        .mass(block.mass)
+        .sleep_threshold(Some(block.sleep_threshold))
         .build();
     let rigid_body_handle = bodies.insert(rigid_body);
-    let collider = ColliderDesc::new(ShapeHandle::new(Cuboid::new(Vector2::new(15., 16.))))
+    let collider = collider_desc(shape.shape_handle(), material)
         .translation(Vector2::new(0., 0.))
+        .ccd_enabled(block.ccd)
+        .collision_groups(Group::Blocks.collision_groups())
         .build(BodyPartHandle(rigid_body_handle, 0));
     let collider_handle = colliders.insert(collider);
+    (rigid_body_handle, collider_handle)
+}
+
+pub fn create_normal_block(
+    xy: (f32, f32),
+    shape: BlockShape,
+    block: &crate::settings::Block,
+    materials: &Materials,
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) {
+    let (rigid_body_handle, collider_handle) =
+        create_block_body(xy, shape, block, &materials.normal, bodies, colliders);
     compy.insert((
         SpriteXY(xy.0, xy.1),
         SpriteUV(352., 144.),
         SpriteWH(32., 32.),
         SpriteR(0., -16., -16.),
+        next_sprite_ids(shape.cells().len() as u32),
         PhysicsBody(rigid_body_handle),
         PhysicsCollider(collider_handle),
         SyncSpriteToPhysics,
+        shape,
         HP(1),
         TakeCursorDamage,
         KillUpon0HP,
+        BlockKind::Normal,
     ));
 }
 
-pub fn create_normal_block_particles(
+// a crate that explodes when destroyed, taking its neighbours with it
+pub fn create_explosive_block(
     xy: (f32, f32),
+    shape: BlockShape,
+    block: &crate::settings::Block,
+    materials: &Materials,
     compy: &Compy,
     bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) {
+    let (rigid_body_handle, collider_handle) =
+        create_block_body(xy, shape, block, &materials.explosive, bodies, colliders);
+    compy.insert((
+        SpriteXY(xy.0, xy.1),
+        SpriteUV(640., 144.),
+        SpriteWH(32., 32.),
+        SpriteR(0., -16., -16.),
+        next_sprite_ids(shape.cells().len() as u32),
+        PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
+        SyncSpriteToPhysics,
+        shape,
+        HP(1),
+        TakeCursorDamage,
+        KillUpon0HP,
+        BlockKind::Explosive,
+        Explosive {
+            radius: 56.,
+            damage: 2,
+            impulse: 160.,
+        },
+    ));
+}
+
+// a block sent by the other player in versus, it takes two hits
+pub fn create_garbage_block(
+    xy: (f32, f32),
+    shape: BlockShape,
+    block: &crate::settings::Block,
+    materials: &Materials,
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) {
+    let (rigid_body_handle, collider_handle) =
+        create_block_body(xy, shape, block, &materials.garbage, bodies, colliders);
+    compy.insert((
+        SpriteXY(xy.0, xy.1),
+        SpriteUV(448., 144.),
+        SpriteWH(32., 32.),
+        SpriteR(0., -16., -16.),
+        next_sprite_ids(shape.cells().len() as u32),
+        PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
+        SyncSpriteToPhysics,
+        shape,
+        HP(2),
+        TakeCursorDamage,
+        KillUpon0HP,
+        BlockKind::Garbage,
+    ));
+}
+
+// a block for puzzle mode, cleared by matching it with others of its color
+#[allow(clippy::too_many_arguments)]
+pub fn create_colored_block(
+    xy: (f32, f32),
+    color: u8,
+    shape: BlockShape,
+    block: &crate::settings::Block,
+    materials: &Materials,
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) {
+    let (rigid_body_handle, collider_handle) =
+        create_block_body(xy, shape, block, &materials.colored, bodies, colliders);
+    let uv = BLOCK_COLOR_UVS[color as usize];
+    compy.insert((
+        SpriteXY(xy.0, xy.1),
+        SpriteUV(uv.0, uv.1),
+        SpriteWH(32., 32.),
+        SpriteR(0., -16., -16.),
+        next_sprite_ids(shape.cells().len() as u32),
+        PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
+        SyncSpriteToPhysics,
+        shape,
+        HP(1),
+        TakeCursorDamage,
+        KillUpon0HP,
+        BlockKind::Colored(color),
+        BlockColor(color),
+    ));
+}
+
+// a piece of a destroyed block, drawn with its part of the block's sprite
+pub fn create_fragment(
+    piece: &Piece,
+    block: &crate::settings::Block,
+    materials: &Materials,
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) {
+    let (w, h) = piece.wh;
+    let rigid_body = RigidBodyDesc::new()
+        .translation(piece.xy)
+        .rotation(piece.rotation)
+        .velocity(Velocity::new(piece.velocity, piece.angular_velocity))
+        .mass(block.mass * w * h / 1024.)
+        .sleep_threshold(Some(block.sleep_threshold))
+        .build();
+    let rigid_body_handle = bodies.insert(rigid_body);
+    // a pixel narrower, like a whole block
+    let shape = ShapeHandle::new(Cuboid::new(Vector2::new(w / 2. - 0.5, h / 2.)));
+    let collider = collider_desc(shape, &materials.get(piece.kind))
+        .ccd_enabled(block.ccd)
+        .collision_groups(Group::Blocks.collision_groups())
+        .build(BodyPartHandle(rigid_body_handle, 0));
+    let collider_handle = colliders.insert(collider);
+    compy.insert((
+        SpriteXY(piece.xy.x, piece.xy.y),
+        SpriteUV(piece.uv.0, piece.uv.1),
+        SpriteWH(w, h),
+        SpriteR(piece.rotation, -w / 2., -h / 2.),
+        next_sprite_id(),
+        PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
+        SyncSpriteToPhysics,
+        HP(1),
+        TakeCursorDamage,
+        KillUpon0HP,
+        Fragment {
+            kind: piece.kind,
+            wh: piece.wh,
+        },
+    ));
+}
+
+// creates a small bit of debris that only bounces off walls and disappears after a while
+pub fn create_particle(
+    xy: (f32, f32),
+    velocity: (f32, f32),
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
 ) {
     let rigid_body = RigidBodyDesc::new()
         .translation(Vector2::new(xy.0, xy.1))
-        .velocity(Velocity::new(Vector2::new(-8., 16.), -2.))
+        .velocity(Velocity::new(Vector2::new(velocity.0, velocity.1), -2.))
+        .mass(0.001)
         .build();
     let rigid_body_handle = bodies.insert(rigid_body);
+    let collider = ColliderDesc::new(ShapeHandle::new(Cuboid::new(Vector2::new(4., 4.))))
+        .collision_groups(Group::Particles.collision_groups())
+        .build(BodyPartHandle(rigid_body_handle, 0));
+    let collider_handle = colliders.insert(collider);
     compy.insert((
         SpriteXY(xy.0, xy.1),
         SpriteUV(352., 144.),
-        SpriteWH(16., 16.),
-        SpriteR(0., -8., -8.),
+        SpriteWH(8., 8.),
+        SpriteR(0., -4., -4.),
+        next_sprite_id(),
         PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
         SyncSpriteToPhysics,
+        KillAfter(0.6),
     ));
 }
 
-pub fn create_cursor(compy: &Compy) {
+// the hopper, standing with its feet at xy
+pub fn create_hopper(
+    xy: (f32, f32),
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) {
+    let rigid_body = RigidBodyDesc::new()
+        .translation(Vector2::new(xy.0, xy.1 - 15.))
+        .mass(0.5) // about half a block
+        .kinematic_rotations(true)
+        .build();
+    let rigid_body_handle = bodies.insert(rigid_body);
+    let collider = ColliderDesc::new(ShapeHandle::new(Cuboid::new(Vector2::new(12., 15.))))
+        .collision_groups(Group::Hopper.collision_groups())
+        .build(BodyPartHandle(rigid_body_handle, 0));
+    let collider_handle = colliders.insert(collider);
+    compy.insert((
+        SpriteXY(xy.0, xy.1 - 15.),
+        SpriteUV(0., 176.),
+        SpriteWH(32., 32.),
+        SpriteR(0., -16., -17.),
+        next_sprite_id(),
+        PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
+        SyncSpriteToPhysics,
+        Hopper {
+            anim: 0.,
+            wall_jump: 0.,
+        },
+    ));
+}
+
+// the cursor, and its hitbox: a sensor on a kinematic body that follows the cursor's cell
+pub fn create_cursor(
+    size: f32,
+    compy: &Compy,
+    bodies: &mut DefaultBodySet<f32>,
+    colliders: &mut DefaultColliderSet<f32>,
+) -> DefaultColliderHandle {
+    let rigid_body = RigidBodyDesc::new()
+        .translation(Vector2::new(-99999., -99999.))
+        .status(BodyStatus::Kinematic)
+        .build();
+    let rigid_body_handle = bodies.insert(rigid_body);
+    let collider = ColliderDesc::new(ShapeHandle::new(Cuboid::new(Vector2::new(
+        size / 2.,
+        size / 2.,
+    ))))
+    .sensor(true)
+    .collision_groups(Group::Cursor.collision_groups())
+    .build(BodyPartHandle(rigid_body_handle, 0));
+    let collider_handle = colliders.insert(collider);
+
     compy.insert((
         SpriteXY(-99999., -99999.),
         SpriteUV(576., 208.),
         SpriteWH(32., 32.),
         SpriteR(0., 0., 0.),
+        next_sprite_id(),
         CursorSnapSpriteToGrid,
         SetUVOnLMBUp(576., 208.),
         SetUVOnLMBDown(576. + 32., 208.),
         CursorEmitDestroyEventOnLMBDown,
+        PhysicsBody(rigid_body_handle),
+        PhysicsCollider(collider_handle),
+    ));
+    compy.insert((
+        SpriteXY(-99999., -99999.),
+        SpriteUV(0., 0.),
+        SpriteWH(16., 16.),
+        SpriteR(0., 0., 0.),
+        next_sprite_id(),
+        CursorToolIcon,
     ));
+    collider_handle
 }
@@ -0,0 +1,37 @@
+// Assets compiled into the binary (--features embed-assets)
+//  Read straight from memory through archive::Assets, unless --assets or $BLOCKHOP_ASSETS
+//  name a directory to use instead.
+macro_rules! embed {
+    ($($path:expr),* $(,)*) => {
+        &[$(($path, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path)) as &[u8])),*]
+    };
+}
+
+const ASSETS: &[(&str, &[u8])] = embed![
+    "textures/mastercomp.png",
+    "shaders/sprite.vert",
+    "shaders/sprite.frag",
+    "shaders/wireframe.vert",
+    "shaders/wireframe.frag",
+    "audio/sfx/block_spawn.wav",
+    "audio/sfx/block_land.wav",
+    "audio/sfx/block_hit.wav",
+    "audio/sfx/block_destroyed.wav",
+    "audio/sfx/game_over.wav",
+    "audio/music/menu.wav",
+    "audio/music/game.wav",
+    "audio/music/game_intense.wav",
+    "levels/default.toml",
+];
+
+// the names of the embedded assets, eg. "textures/mastercomp.png"
+pub fn names() -> impl Iterator<Item = &'static str> {
+    ASSETS.iter().map(|&(name, _)| name)
+}
+
+pub fn get(name: &str) -> Option<&'static [u8]> {
+    ASSETS
+        .iter()
+        .find(|&&(asset, _)| asset == name)
+        .map(|&(_, bytes)| bytes)
+}
@@ -0,0 +1,53 @@
+use crate::{audio::AudioErr, render::RenderErr, update::UpdateErr};
+use std::{fmt, thread::JoinHandle};
+
+// Everything that can end the game early, as reported by main
+#[derive(Debug)]
+pub enum Error {
+    Update(UpdateErr),
+    Render(RenderErr),
+    Audio(AudioErr),
+    Panicked(&'static str), // thread name
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Update(e) => write!(f, "update: {}", e),
+            Error::Render(e) => write!(f, "render: {}", e),
+            Error::Audio(e) => write!(f, "audio: {}", e),
+            Error::Panicked(name) => write!(f, "the {} thread panicked", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<UpdateErr> for Error {
+    fn from(e: UpdateErr) -> Error {
+        Error::Update(e)
+    }
+}
+
+impl From<RenderErr> for Error {
+    fn from(e: RenderErr) -> Error {
+        Error::Render(e)
+    }
+}
+
+impl From<AudioErr> for Error {
+    fn from(e: AudioErr) -> Error {
+        Error::Audio(e)
+    }
+}
+
+// waits for a thread, turning both its error and a panic into an Error
+pub fn join<E: Into<Error>>(
+    name: &'static str,
+    handle: JoinHandle<Result<(), E>>,
+) -> Result<(), Error> {
+    match handle.join() {
+        Ok(res) => res.map_err(Into::into),
+        Err(_) => Err(Error::Panicked(name)),
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{components::BlockKind, settings::Fracture};
+use nalgebra::Vector2;
+
+// A rectangular piece of a destroyed block, the block's cells being the first pieces
+#[derive(Copy, Clone, Debug)]
+pub struct Piece {
+    pub kind: BlockKind,
+    pub xy: Vector2<f32>, // center
+    pub rotation: f32,
+    pub velocity: Vector2<f32>,
+    pub angular_velocity: f32,
+    pub uv: (f32, f32), // the top left of its part of the block's sprite
+    pub wh: (f32, f32),
+}
+
+// the pieces a piece breaks into, none if they'd be smaller than the fracture's min_size
+//  Two pieces split it across its longer side. Each piece keeps moving the way its part of
+//  the parent was, spin included.
+pub fn split(piece: &Piece, fracture: Fracture) -> Vec<Piece> {
+    let (w, h) = piece.wh;
+    let (columns, rows) = match fracture.pieces {
+        2 if w >= h => (2, 1),
+        2 => (1, 2),
+        4 => (2, 2),
+        _ => return Vec::new(),
+    };
+    let wh = (w / columns as f32, h / rows as f32);
+    if wh.0.min(wh.1) < fracture.min_size {
+        return Vec::new();
+    }
+
+    let (sin, cos) = piece.rotation.sin_cos();
+    let mut pieces = Vec::new();
+    for column in 0..columns {
+        for row in 0..rows {
+            // from the parent's center, before it's rotated
+            let x = (column as f32 + 0.5) * wh.0 - w / 2.;
+            let y = (row as f32 + 0.5) * wh.1 - h / 2.;
+            let offset = Vector2::new(x * cos - y * sin, x * sin + y * cos);
+            let spin = Vector2::new(-offset.y, offset.x) * piece.angular_velocity;
+            pieces.push(Piece {
+                xy: piece.xy + offset,
+                velocity: piece.velocity + spin,
+                uv: (
+                    piece.uv.0 + column as f32 * wh.0,
+                    piece.uv.1 + row as f32 * wh.1,
+                ),
+                wh,
+                ..*piece
+            });
+        }
+    }
+    pieces
+}
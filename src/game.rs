@@ -0,0 +1,1546 @@
+use crate::{
+    audio::{AudioEvent, Sound},
+    board::{Board, Cell},
+    components::*,
+    fracture::Piece,
+    input::Input,
+    level::Level,
+    render::RenderState,
+    settings::{self, Settings},
+    spawn::{RandomizerKind, SpawnQueue},
+    time::get_microseconds_as_u64,
+    tools::{Tool, Toolbelt},
+};
+use compy::{compy::*, compy_builder::CompyBuilder, key::Key};
+use crossbeam_channel::Sender;
+use nalgebra::Vector2;
+use ncollide2d::{
+    bounding_volume::AABB,
+    pipeline::{narrow_phase::ContactEvent, object::CollisionGroups},
+    query::{self, Proximity},
+    shape::{Ball, Compound, ConvexPolygon, Cuboid, Shape},
+};
+use nphysics2d::{
+    force_generator::DefaultForceGeneratorSet,
+    joint::{DefaultJointConstraintHandle, DefaultJointConstraintSet, MouseConstraint},
+    math::{Force, ForceType, Isometry, Point, Velocity},
+    object::{
+        Body, BodyPartHandle, BodyStatus, Collider, DefaultBodyHandle, DefaultBodySet,
+        DefaultColliderHandle, DefaultColliderSet, RigidBody, RigidBodyDesc,
+    },
+    world::{DefaultGeometricalWorld, DefaultMechanicalWorld},
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+#[derive(Debug)]
+pub enum GameErr {
+    MissingBody(DefaultBodyHandle),
+    MissingCollider(DefaultColliderHandle),
+}
+
+impl fmt::Display for GameErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GameErr::MissingBody(h) => write!(f, "entity refers to missing rigid body {:?}", h),
+            GameErr::MissingCollider(h) => write!(f, "entity refers to missing collider {:?}", h),
+        }
+    }
+}
+
+impl std::error::Error for GameErr {}
+
+fn get_rigid_body(
+    bodies: &DefaultBodySet<f32>,
+    handle: DefaultBodyHandle,
+) -> Result<&RigidBody<f32>, GameErr> {
+    bodies
+        .rigid_body(handle)
+        .ok_or(GameErr::MissingBody(handle))
+}
+
+fn get_collider(
+    colliders: &DefaultColliderSet<f32>,
+    handle: DefaultColliderHandle,
+) -> Result<&Collider<f32, DefaultBodyHandle>, GameErr> {
+    colliders
+        .get(handle)
+        .ok_or(GameErr::MissingCollider(handle))
+}
+
+// the outline of a shape placed at iso, as convex polygons, for debug drawing
+//  Anything that isn't a cuboid, ball, convex polygon or a compound of those is drawn as its
+//  bounding box.
+fn outlines(shape: &dyn Shape<f32>, iso: &Isometry<f32>, out: &mut Vec<Vec<(f32, f32)>>) {
+    let points: Vec<Point<f32>> = if let Some(cuboid) = shape.as_shape::<Cuboid<f32>>() {
+        let half = cuboid.half_extents();
+        [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)]
+            .iter()
+            .map(|&(x, y)| Point::new(half.x * x, half.y * y))
+            .collect()
+    } else if let Some(ball) = shape.as_shape::<Ball<f32>>() {
+        (0..16)
+            .map(|i| {
+                let angle = i as f32 / 16. * std::f32::consts::PI * 2.;
+                Point::new(angle.cos(), angle.sin()) * ball.radius()
+            })
+            .collect()
+    } else if let Some(polygon) = shape.as_shape::<ConvexPolygon<f32>>() {
+        polygon.points().to_vec()
+    } else if let Some(compound) = shape.as_shape::<Compound<f32>>() {
+        for (part_iso, part) in compound.shapes() {
+            outlines(&**part, &(iso * part_iso), out);
+        }
+        return;
+    } else {
+        let aabb = shape.aabb(iso);
+        let (min, max) = (aabb.mins(), aabb.maxs());
+        out.push(vec![
+            (min.x, min.y),
+            (max.x, min.y),
+            (max.x, max.y),
+            (min.x, max.y),
+        ]);
+        return;
+    };
+    out.push(
+        points
+            .iter()
+            .map(|point| {
+                let point = iso * point;
+                (point.x, point.y)
+            })
+            .collect(),
+    );
+}
+
+#[derive(Copy, Clone)]
+struct Keys {
+    none: Key,
+    sprite_xy: Key,
+    sprite_uv: Key,
+    sprite_wh: Key,
+    sprite_r: Key,
+    sprite_id: Key,
+    physics_body: Key,
+    physics_collider: Key,
+    sync_sprite_to_physics: Key,
+    cursor_snap_sprite_to_grid: Key,
+    cursor_emit_destroy_event_on_lmb_down: Key,
+    set_uv_on_lmb_up: Key,
+    set_uv_on_lmb_down: Key,
+    hp: Key,
+    take_cursor_damage: Key,
+    kill_upon_0_hp: Key,
+    kill_after: Key,
+    block_color: Key,
+    explosive: Key,
+    block_kind: Key,
+    block_shape: Key,
+    fragment: Key,
+    cursor_tool_icon: Key,
+    hopper: Key,
+}
+
+// Which rules a round is played by
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Classic, // click blocks away before the well overflows
+    Puzzle,  // colored blocks, touching groups of one color clear themselves
+    Hop,     // classic, with a character to keep from being crushed
+}
+
+impl Mode {
+    // how columns are picked when the level doesn't say, classic keeps its uniform spawns
+    fn randomizer(self) -> RandomizerKind {
+        match self {
+            Mode::Classic => RandomizerKind::Uniform,
+            Mode::Puzzle => RandomizerKind::Bag, // fills the columns evenly
+            Mode::Hop => RandomizerKind::Weighted, // there's someone down there
+        }
+    }
+
+    // each mode keeps its own high score table
+    pub fn scores_file(self) -> &'static str {
+        match self {
+            Mode::Classic => "highscores.bin",
+            Mode::Puzzle => "highscores_puzzle.bin",
+            Mode::Hop => "highscores_hop.bin",
+        }
+    }
+}
+
+// Something that happened in the physics world during a step
+#[derive(Copy, Clone, Debug)]
+pub enum GameEvent {
+    BlockLanded { xy: (f32, f32), strength: f32 }, // on a wall or the floor, xy under the block
+    BlockHitBlock { strength: f32 },
+}
+
+// turns the contacts that started during the last step into game events, strength being the
+// relative speed of the two bodies
+fn contact_events(
+    geometrical_world: &DefaultGeometricalWorld<f32>,
+    colliders: &DefaultColliderSet<f32>,
+    bodies: &DefaultBodySet<f32>,
+) -> Vec<GameEvent> {
+    let mut events = Vec::new();
+    for event in geometrical_world.contact_events().iter() {
+        let (c1, c2) = match *event {
+            ContactEvent::Started(c1, c2) => (c1, c2),
+            ContactEvent::Stopped(..) => continue,
+        };
+        // either may have been removed since
+        let (c1, c2) = match (colliders.get(c1), colliders.get(c2)) {
+            (Some(c1), Some(c2)) => (c1, c2),
+            _ => continue,
+        };
+        let velocity = |collider: &Collider<f32, DefaultBodyHandle>| {
+            bodies
+                .rigid_body(collider.body())
+                .map(|body| body.velocity().linear)
+                .unwrap_or_else(Vector2::zeros)
+        };
+        let strength = (velocity(c1) - velocity(c2)).norm();
+
+        let is = |collider: &Collider<f32, DefaultBodyHandle>, group: Group| {
+            group.is_member(collider.collision_groups())
+        };
+        if is(c1, Group::Blocks) && is(c2, Group::Blocks) {
+            events.push(GameEvent::BlockHitBlock { strength });
+        } else if is(c1, Group::Walls) || is(c2, Group::Walls) {
+            let block = if is(c1, Group::Blocks) { c1 } else { c2 };
+            if !is(block, Group::Blocks) {
+                continue;
+            }
+            let xy = block.position().translation.vector;
+            events.push(GameEvent::BlockLanded {
+                xy: (xy.x, xy.y + 16.),
+                strength,
+            });
+        }
+    }
+    events
+}
+
+// pushes away the blocks and debris around an explosion, returns the blocks hit and the
+// damage each takes, source being the exploding collider (which is left alone)
+fn blast(
+    geometrical_world: &DefaultGeometricalWorld<f32>,
+    colliders: &DefaultColliderSet<f32>,
+    bodies: &mut DefaultBodySet<f32>,
+    center: Vector2<f32>,
+    source: Option<DefaultColliderHandle>,
+    explosive: Explosive,
+) -> Vec<(DefaultColliderHandle, u8)> {
+    let r = Vector2::new(explosive.radius, explosive.radius);
+    let aabb = AABB::new(Point::from(center - r), Point::from(center + r));
+    let groups = CollisionGroups::new();
+
+    let mut hit = Vec::new();
+    for (handle, collider) in geometrical_world.interferences_with_aabb(colliders, &aabb, &groups) {
+        let is_block = Group::Blocks.is_member(collider.collision_groups());
+        if Some(handle) == source
+            || !(is_block || Group::Particles.is_member(collider.collision_groups()))
+        {
+            continue;
+        }
+        let offset = collider.position().translation.vector - center;
+        let distance = offset.norm();
+        if distance >= explosive.radius {
+            continue;
+        }
+
+        // strongest at the center, straight up if right on top of it
+        let falloff = 1. - distance / explosive.radius;
+        let direction = if distance > 0.001 {
+            offset / distance
+        } else {
+            Vector2::new(0., -1.)
+        };
+        if let Some(body) = bodies.rigid_body_mut(collider.body()) {
+            let force = Force::linear(direction * explosive.impulse * falloff);
+            body.apply_force(0, &force, ForceType::VelocityChange, true);
+        }
+        if is_block {
+            hit.push((handle, (explosive.damage as f32 * falloff).ceil() as u8));
+        }
+    }
+    hit
+}
+
+// the blocks the cursor's collider overlaps when it's centered at xy, nearest first
+fn cursor_targets(
+    geometrical_world: &DefaultGeometricalWorld<f32>,
+    colliders: &DefaultColliderSet<f32>,
+    cursor: DefaultColliderHandle,
+    xy: Vector2<f32>,
+) -> Result<Vec<DefaultColliderHandle>, GameErr> {
+    let shape = get_collider(colliders, cursor)?.shape();
+    let iso = Isometry::translation(xy.x, xy.y);
+    let aabb = shape.aabb(&iso);
+    let groups = Group::Cursor.collision_groups();
+
+    let mut targets = Vec::new();
+    for (handle, collider) in geometrical_world.interferences_with_aabb(colliders, &aabb, &groups) {
+        if !Group::Blocks.is_member(collider.collision_groups()) {
+            continue;
+        }
+        let proximity =
+            query::proximity(&iso, &**shape, collider.position(), &**collider.shape(), 0.);
+        if proximity == Proximity::Intersecting {
+            let distance = (collider.position().translation.vector - xy).norm();
+            targets.push((handle, distance));
+        }
+    }
+    targets.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(targets.into_iter().map(|(handle, _)| handle).collect())
+}
+
+// the grid cells a block's cells are resting in, none while it's moving
+fn resting_cells(
+    board: &Board,
+    body: &RigidBody<f32>,
+    shape: BlockShape,
+) -> Vec<((usize, usize), bool)> {
+    let velocity = body.velocity().linear;
+    if velocity.x.abs() >= 1. || velocity.y.abs() >= 1. {
+        return Vec::new();
+    }
+    let position = body.position();
+    shape
+        .cells()
+        .iter()
+        .filter_map(|&(x, y)| {
+            let xy = position * Point::new(x, y);
+            board.cell_at((xy.x, xy.y))
+        })
+        .collect()
+}
+
+// rebuilds the board from where the blocks came to rest, fragments aren't on it
+fn sync_board(
+    board: &mut Board,
+    compy: &mut Compy,
+    keys: Keys,
+    bodies: &DefaultBodySet<f32>,
+) -> Result<(), GameErr> {
+    board.clear();
+    let mut res = Ok(());
+    compy.iterate_mut(
+        keys.block_kind + keys.block_shape + keys.physics_body,
+        keys.none,
+        |kind: &BlockKind, shape: &BlockShape, physics_body: &PhysicsBody| {
+            res = res.and_then(|_| {
+                let body = get_rigid_body(bodies, physics_body.0)?;
+                for (cell, aligned) in resting_cells(board, body, *shape) {
+                    board.insert(
+                        cell,
+                        Cell {
+                            kind: *kind,
+                            aligned,
+                        },
+                    );
+                }
+                Ok(())
+            });
+            false
+        },
+    );
+    res
+}
+
+// A mouse press in the well, a click until it's held on a block long enough to drag it
+struct Press {
+    held: f32, // seconds
+    target: Option<DefaultColliderHandle>,
+}
+
+// A block being dragged by a mouse joint, it keeps its velocity when let go
+struct Drag {
+    joint: DefaultJointConstraintHandle,
+    body: DefaultBodyHandle,
+}
+
+// ticks two wells side by side, what one player clears drops into the other's well
+pub fn versus_tick(
+    left: &mut Game,
+    right: &mut Game,
+    dt: f32,
+    inputs: (&Input, &Input),
+    audio_send: &Sender<AudioEvent>,
+) -> Result<(), GameErr> {
+    left.tick(dt, inputs.0, audio_send)?;
+    right.tick(dt, inputs.1, audio_send)?;
+    right.add_garbage(left.take_garbage());
+    left.add_garbage(right.take_garbage());
+    Ok(())
+}
+
+// A single round: the physics world, the ecs and the score
+pub struct Game {
+    camw: f32,
+    camh: f32,
+    gravity: f32,
+    mode: Mode,
+    block: settings::Block,
+    materials: settings::Materials,
+    fracture: settings::Fractures,
+    puzzle: settings::Puzzle,
+    mechanical_world: DefaultMechanicalWorld<f32>,
+    geometrical_world: DefaultGeometricalWorld<f32>,
+    bodies: DefaultBodySet<f32>,
+    colliders: DefaultColliderSet<f32>,
+    joint_constraints: DefaultJointConstraintSet<f32>,
+    force_generators: DefaultForceGeneratorSet<f32>,
+    compy: Compy,
+    keys: Keys,
+    rng: StdRng,
+    spawns: SpawnQueue,
+    board: Board, // the resting blocks, as of the last tick
+    tools: Toolbelt,
+    world: DefaultBodyHandle,
+    cursor: DefaultColliderHandle,
+    drag_hold_time: f32,
+    preview: usize, // queued blocks shown
+    press: Option<Press>,
+    drag: Option<Drag>,
+
+    // extra data
+    stat_counter: f32,
+    block_drop_counter: f32,
+    overflow_counter: f32,
+    last_intensity: f32,
+    chain: u32,       // matches cleared in a row, each one while the last was still recent
+    chain_timer: f32, // seconds left for another match to continue the chain
+    frozen: Vec<DefaultBodyHandle>, // made static by the freeze tool
+    freeze_timer: f32,
+    flip_timer: f32,    // seconds of upside down gravity left
+    garbage_out: u32,   // blocks destroyed that haven't been sent to an opponent yet
+    garbage_queue: u32, // blocks sent by an opponent, still to drop
+    garbage_counter: f32,
+    over: bool,
+    pub seed: u64,
+    pub score: u32,
+    pub blocks_destroyed: u32,
+    pub elapsed: f32,
+}
+
+impl Game {
+    // seconds a block has to rest above the well before the round is lost
+    const OVERFLOW_TIME: f32 = 1.;
+    const DEBRIS_STRENGTH: f32 = 24.;
+    const FREEZE_TIME: f32 = 5.;
+    const FLIP_TIME: f32 = 1.5;
+    const GARBAGE_PER_BLOCKS: u32 = 3; // blocks destroyed for each garbage block sent
+    const GARBAGE_INTERVAL: f32 = 0.25; // seconds between garbage blocks dropping in
+    const DRAG_STRENGTH: f32 = 2000.; // the mouse joint's force limit per unit of mass
+    const BOMB: Explosive = Explosive {
+        radius: 72.,
+        damage: 3,
+        impulse: 200.,
+    };
+
+    // the seed decides everything random in the round, so it can be replayed
+    pub fn new(settings: &Settings, level: &Level, mode: Mode, seed: u64) -> Game {
+        let camh = settings.camera.height;
+
+        // world
+        let mut mechanical_world =
+            DefaultMechanicalWorld::new(Vector2::new(0., settings.physics.gravity));
+        let parameters = &mut mechanical_world.integration_parameters;
+        parameters.max_velocity_iterations = settings.physics.velocity_iterations as usize;
+        parameters.max_position_iterations = settings.physics.position_iterations as usize;
+        let geometrical_world = DefaultGeometricalWorld::<f32>::new();
+        let mut bodies = DefaultBodySet::new();
+        let mut colliders = DefaultColliderSet::new();
+        let joint_constraints = DefaultJointConstraintSet::<f32>::new();
+        let force_generators = DefaultForceGeneratorSet::<f32>::new();
+
+        // the ecs
+        let compy = CompyBuilder::new()
+            .with::<SpriteXY>()
+            .with::<SpriteUV>()
+            .with::<SpriteWH>()
+            .with::<SpriteR>()
+            .with::<SpriteId>()
+            .with::<PhysicsBody>()
+            .with::<PhysicsCollider>()
+            .with::<SyncSpriteToPhysics>()
+            .with::<CursorSnapSpriteToGrid>()
+            .with::<CursorEmitDestroyEventOnLMBDown>()
+            .with::<SetUVOnLMBDown>()
+            .with::<SetUVOnLMBUp>()
+            .with::<HP>()
+            .with::<TakeCursorDamage>()
+            .with::<KillUpon0HP>()
+            .with::<KillAfter>()
+            .with::<BlockColor>()
+            .with::<Explosive>()
+            .with::<BlockKind>()
+            .with::<BlockShape>()
+            .with::<Fragment>()
+            .with::<CursorToolIcon>()
+            .with::<Hopper>()
+            .build();
+        let keys = Keys {
+            none: Key::default(),
+            sprite_xy: compy.get_key_for::<SpriteXY>(),
+            sprite_uv: compy.get_key_for::<SpriteUV>(),
+            sprite_wh: compy.get_key_for::<SpriteWH>(),
+            sprite_r: compy.get_key_for::<SpriteR>(),
+            sprite_id: compy.get_key_for::<SpriteId>(),
+            physics_body: compy.get_key_for::<PhysicsBody>(),
+            physics_collider: compy.get_key_for::<PhysicsCollider>(),
+            sync_sprite_to_physics: compy.get_key_for::<SyncSpriteToPhysics>(),
+            cursor_snap_sprite_to_grid: compy.get_key_for::<CursorSnapSpriteToGrid>(),
+            cursor_emit_destroy_event_on_lmb_down: compy
+                .get_key_for::<CursorEmitDestroyEventOnLMBDown>(),
+            set_uv_on_lmb_up: compy.get_key_for::<SetUVOnLMBUp>(),
+            set_uv_on_lmb_down: compy.get_key_for::<SetUVOnLMBDown>(),
+            hp: compy.get_key_for::<HP>(),
+            take_cursor_damage: compy.get_key_for::<TakeCursorDamage>(),
+            kill_upon_0_hp: compy.get_key_for::<KillUpon0HP>(),
+            kill_after: compy.get_key_for::<KillAfter>(),
+            block_color: compy.get_key_for::<BlockColor>(),
+            explosive: compy.get_key_for::<Explosive>(),
+            block_kind: compy.get_key_for::<BlockKind>(),
+            block_shape: compy.get_key_for::<BlockShape>(),
+            fragment: compy.get_key_for::<Fragment>(),
+            cursor_tool_icon: compy.get_key_for::<CursorToolIcon>(),
+            hopper: compy.get_key_for::<Hopper>(),
+        };
+
+        // the world is a special permanent handle that is unmoving
+        let world = RigidBodyDesc::new().status(BodyStatus::Static).build();
+        let world = bodies.insert(world);
+        // the well, moved down so its bottom is at the bottom of the screen
+        let dy = camh - level.height;
+        for wall in level.walls.iter() {
+            crate::components::create_wall(
+                (wall.xy.0, wall.xy.1 + dy),
+                wall.wh,
+                wall.material.as_ref().unwrap_or(&settings.materials.wall),
+                &compy,
+                world,
+                &mut colliders,
+            );
+        }
+        for sprite in level.sprites.iter() {
+            crate::components::create_sprite(
+                (sprite.xy.0, sprite.xy.1 + dy),
+                sprite.uv,
+                sprite.wh,
+                &compy,
+            );
+        }
+        if mode == Mode::Hop {
+            // in the middle of the well floor
+            crate::components::create_hopper(
+                (176., camh - 32.),
+                &compy,
+                &mut bodies,
+                &mut colliders,
+            );
+        }
+        let cursor = crate::components::create_cursor(
+            settings.controls.cursor_size,
+            &compy,
+            &mut bodies,
+            &mut colliders,
+        );
+
+        // what drops first is decided up front, so it can be previewed
+        let mut rng = StdRng::seed_from_u64(seed);
+        let colors = match mode {
+            Mode::Puzzle => Some(settings.puzzle.colors),
+            _ => None,
+        };
+        let randomizer = level.spawns.randomizer.unwrap_or_else(|| mode.randomizer());
+        let spawns = SpawnQueue::new(
+            settings.block.preview as usize,
+            crate::spawn::randomizer(randomizer, &level.spawns.script),
+            settings.block.explosive_chance,
+            colors,
+            &level.spawns.shapes,
+            &mut rng,
+        );
+
+        Game {
+            camw: settings.camera.width,
+            camh,
+            gravity: settings.physics.gravity,
+            mode,
+            block: settings.block,
+            materials: settings.materials,
+            fracture: settings.fracture,
+            puzzle: settings.puzzle,
+            mechanical_world,
+            geometrical_world,
+            bodies,
+            colliders,
+            joint_constraints,
+            force_generators,
+            compy,
+            keys,
+            rng,
+            spawns,
+            board: Board::new(camh - 32.),
+            tools: Toolbelt::default(),
+            world,
+            cursor,
+            drag_hold_time: settings.controls.drag_hold_time,
+            preview: settings.block.preview as usize,
+            press: None,
+            drag: None,
+            stat_counter: 0.,
+            block_drop_counter: 0.,
+            overflow_counter: 0.,
+            last_intensity: 0.,
+            chain: 0,
+            chain_timer: 0.,
+            frozen: Vec::new(),
+            freeze_timer: 0.,
+            flip_timer: 0.,
+            garbage_out: 0,
+            garbage_queue: 0,
+            garbage_counter: 0.,
+            over: false,
+            seed,
+            score: 0,
+            blocks_destroyed: 0,
+            elapsed: 0.,
+        }
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    // true once the stack has overflowed the well
+    pub fn is_over(&self) -> bool {
+        self.over
+    }
+
+    // the garbage blocks this round has earned to send to an opponent since the last call
+    pub fn take_garbage(&mut self) -> u32 {
+        let sent = self.garbage_out / Self::GARBAGE_PER_BLOCKS;
+        self.garbage_out %= Self::GARBAGE_PER_BLOCKS;
+        sent
+    }
+
+    // queues garbage blocks sent by an opponent
+    pub fn add_garbage(&mut self, count: u32) {
+        self.garbage_queue += count;
+    }
+
+    // a hash of every body's motion and the score, two copies of a round fed the same
+    // inputs should always agree on it
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for (handle, _) in self.bodies.iter() {
+            if let Some(body) = self.bodies.rigid_body(handle) {
+                let position = body.position();
+                let velocity = body.velocity();
+                let rotation = position.rotation.angle();
+                let values = [
+                    position.translation.vector.x,
+                    position.translation.vector.y,
+                    rotation,
+                    velocity.linear.x,
+                    velocity.linear.y,
+                    velocity.angular,
+                ];
+                for value in values.iter() {
+                    value.to_bits().hash(&mut hasher);
+                }
+            }
+        }
+        (self.score, self.blocks_destroyed, self.garbage_queue).hash(&mut hasher);
+        self.over.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn tick(
+        &mut self,
+        dt: f32,
+        input: &Input,
+        audio_send: &Sender<AudioEvent>,
+    ) -> Result<(), GameErr> {
+        let Game {
+            camh,
+            mode,
+            block,
+            materials,
+            fracture,
+            puzzle,
+            mechanical_world,
+            geometrical_world,
+            bodies,
+            colliders,
+            joint_constraints,
+            force_generators,
+            compy,
+            keys,
+            board,
+            ..
+        } = self;
+        let camh = *camh;
+        let mode = *mode;
+        let keys = *keys;
+        let ft_start = get_microseconds_as_u64();
+        self.elapsed += dt;
+
+        self.stat_counter += dt;
+        let print_stats = if self.stat_counter > 10. {
+            self.stat_counter -= 10.;
+            true
+        } else {
+            false
+        };
+
+        // drop the next queued block every second
+        self.block_drop_counter += dt;
+        if self.block_drop_counter > 1f32 {
+            self.block_drop_counter -= 1f32;
+
+            let spawn = self.spawns.pop(&mut self.rng, &board.heights());
+            let x = (64 + 16 + spawn.column * 32) as f32;
+            match spawn.kind {
+                BlockKind::Normal => crate::components::create_normal_block(
+                    (x, -16.),
+                    spawn.shape,
+                    block,
+                    materials,
+                    compy,
+                    bodies,
+                    colliders,
+                ),
+                BlockKind::Explosive => crate::components::create_explosive_block(
+                    (x, -16.),
+                    spawn.shape,
+                    block,
+                    materials,
+                    compy,
+                    bodies,
+                    colliders,
+                ),
+                BlockKind::Garbage => crate::components::create_garbage_block(
+                    (x, -16.),
+                    spawn.shape,
+                    block,
+                    materials,
+                    compy,
+                    bodies,
+                    colliders,
+                ),
+                BlockKind::Colored(color) => crate::components::create_colored_block(
+                    (x, -16.),
+                    color,
+                    spawn.shape,
+                    block,
+                    materials,
+                    compy,
+                    bodies,
+                    colliders,
+                ),
+            }
+            let _ = audio_send.send(AudioEvent::Play(Sound::BlockSpawn, 1.));
+
+            compy.update();
+        }
+
+        // garbage from an opponent drops in quickly, one block at a time
+        if self.garbage_queue > 0 {
+            self.garbage_counter += dt;
+            if self.garbage_counter > Self::GARBAGE_INTERVAL {
+                self.garbage_counter -= Self::GARBAGE_INTERVAL;
+                self.garbage_queue -= 1;
+
+                let x = (64 + 16 + self.rng.gen_range(0, 7) * 32) as f32;
+                crate::components::create_garbage_block(
+                    (x, -16.),
+                    BlockShape::Square,
+                    block,
+                    materials,
+                    compy,
+                    bodies,
+                    colliders,
+                );
+                compy.update();
+            }
+        } else {
+            self.garbage_counter = 0.;
+        }
+
+        // update nphysics2d
+        if print_stats {
+            let now = get_microseconds_as_u64();
+            mechanical_world.set_timestep(dt);
+            mechanical_world.step(
+                geometrical_world,
+                bodies,
+                colliders,
+                joint_constraints,
+                force_generators,
+            );
+            println!("nphysics update {:?}", get_microseconds_as_u64() - now);
+        } else {
+            mechanical_world.set_timestep(dt);
+            mechanical_world.step(
+                geometrical_world,
+                bodies,
+                colliders,
+                joint_constraints,
+                force_generators,
+            );
+        }
+
+        // react to what happened during the step
+        let mut particles = Vec::new();
+        for event in contact_events(geometrical_world, colliders, bodies) {
+            let strength = match event {
+                GameEvent::BlockLanded { xy, strength } => {
+                    // hard landings kick up some debris
+                    if strength > Self::DEBRIS_STRENGTH {
+                        particles.push(xy);
+                    }
+                    strength
+                }
+                GameEvent::BlockHitBlock { strength } => strength,
+            };
+            if strength > 4. {
+                let volume = nalgebra::clamp(strength / 64., 0.1, 1.);
+                let _ = audio_send.send(AudioEvent::Play(Sound::BlockLand, volume));
+            }
+        }
+        for xy in particles {
+            for &velocity in [(-24., -32.), (24., -32.)].iter() {
+                crate::components::create_particle(xy, velocity, compy, bodies, colliders);
+            }
+        }
+
+        // the hopper moves off what it touched during the step, and the round ends if it
+        // was crushed
+        let mut crushed = false;
+        let pkey = keys.hopper + keys.sprite_uv + keys.physics_body + keys.physics_collider;
+        let mut res = Ok(());
+        compy.iterate_mut(
+            pkey,
+            keys.none,
+            |hopper: &mut Hopper,
+             sprite_uv: &mut SpriteUV,
+             physics_body: &PhysicsBody,
+             physics_collider: &PhysicsCollider| {
+                res = res.and_then(|_| {
+                    let touching = crate::hopper::touching(
+                        geometrical_world,
+                        colliders,
+                        bodies,
+                        physics_collider.0,
+                    );
+                    let body = bodies
+                        .rigid_body_mut(physics_body.0)
+                        .ok_or(GameErr::MissingBody(physics_body.0))?;
+                    let uv = crate::hopper::control(hopper, body, touching, input, dt);
+                    *sprite_uv = SpriteUV(uv.0, uv.1);
+                    crushed |= touching.crushed;
+                    Ok(())
+                });
+                false
+            },
+        );
+        res?;
+        if crushed && !self.over {
+            self.over = true;
+            let _ = audio_send.send(AudioEvent::Play(Sound::GameOver, 1.));
+        }
+
+        // the board catches up with where blocks came to rest
+        sync_board(board, compy, keys, bodies)?;
+
+        // the music intensifies as resting blocks stack up towards the top of the well
+        let floor = camh - 32.;
+        let well_rows = (floor / 32.).floor() as u32;
+        let stack = board.heights().iter().cloned().max().unwrap_or(0);
+        let intensity = nalgebra::clamp(stack as f32 / well_rows as f32, 0., 1.);
+        if (intensity - self.last_intensity).abs() > 0.05 {
+            self.last_intensity = intensity;
+            let _ = audio_send.send(AudioEvent::Intensity(intensity));
+        }
+
+        // tools that last a while wear off
+        if self.freeze_timer > 0. {
+            self.freeze_timer -= dt;
+            if self.freeze_timer <= 0. {
+                for handle in self.frozen.drain(..) {
+                    if let Some(body) = bodies.rigid_body_mut(handle) {
+                        body.set_status(BodyStatus::Dynamic);
+                        body.activate();
+                    }
+                }
+            }
+        }
+        if self.flip_timer > 0. {
+            self.flip_timer -= dt;
+            if self.flip_timer <= 0. {
+                mechanical_world.gravity = Vector2::new(0., self.gravity);
+            }
+        }
+
+        // the round is lost once a block has rested above the top of the well for a while
+        //  Frozen or flipped blocks don't count, they were put there.
+        let suspended = self.freeze_timer > 0. || self.flip_timer > 0.;
+        if stack > well_rows && !suspended {
+            self.overflow_counter += dt;
+            if self.overflow_counter > Self::OVERFLOW_TIME {
+                self.over = true;
+                let _ = audio_send.send(AudioEvent::Play(Sound::GameOver, 1.));
+            }
+        } else {
+            self.overflow_counter = 0.;
+        }
+
+        // the cursor's grid cell, its center
+        let norm = 80.;
+        let temp_x = ((input.cursor_x - norm) / 32.).round() * 32. + norm;
+        let temp_x = nalgebra::clamp(temp_x, norm, 272.);
+        let norm = camh - 32. - 16.;
+        let temp_y = ((input.cursor_y - norm) / 32.).round() * 32. + norm;
+        let temp_y = nalgebra::clamp(temp_y, -9999999., norm);
+
+        // tool selection
+        self.tools.tick(dt);
+        self.tools.select(input);
+
+        // the cursor's hitbox follows its cell
+        let cursor_xy = Vector2::new(temp_x, temp_y);
+        let cursor_body = get_collider(colliders, self.cursor)?.body();
+        if let Some(body) = bodies.rigid_body_mut(cursor_body) {
+            body.set_position(Isometry::translation(temp_x, temp_y));
+        }
+
+        // a press is a click unless it's held on a block, which then starts a drag
+        if input.lmb_pressed {
+            // cursor "on press" event
+            let pkey = keys.set_uv_on_lmb_down + keys.sprite_uv;
+            compy.iterate_mut(
+                pkey,
+                keys.none,
+                |lmb_down_uv: &SetUVOnLMBDown, sprite_uv: &mut SpriteUV| {
+                    sprite_uv.0 = lmb_down_uv.0;
+                    sprite_uv.1 = lmb_down_uv.1;
+                    false
+                },
+            );
+
+            let targets = cursor_targets(geometrical_world, colliders, self.cursor, cursor_xy)?;
+            self.press = Some(Press {
+                held: 0.,
+                target: targets.first().cloned(),
+            });
+        }
+        if input.focus_lost {
+            self.press = None;
+        }
+        if let Some(press) = &mut self.press {
+            press.held += dt;
+            if press.held >= self.drag_hold_time && self.drag.is_none() {
+                if let Some(target) = press.target {
+                    // grab the block at the cursor point
+                    let handle = colliders.get(target).map(|collider| collider.body());
+                    let rigid_body = handle.and_then(|handle| bodies.rigid_body(handle));
+                    if let (Some(handle), Some(rigid_body)) = (handle, rigid_body) {
+                        let point = Point::new(input.cursor_x, input.cursor_y);
+                        let local = rigid_body.position().inverse_transform_point(&point);
+                        let joint = MouseConstraint::new(
+                            BodyPartHandle(self.world, 0),
+                            BodyPartHandle(handle, 0),
+                            point,
+                            local,
+                            rigid_body.augmented_mass().linear * Self::DRAG_STRENGTH,
+                        );
+                        self.drag = Some(Drag {
+                            joint: joint_constraints.insert(joint),
+                            body: handle,
+                        });
+                    }
+                }
+            }
+        }
+
+        // the dragged block is pulled towards the cursor, until it's let go or destroyed
+        let mut released = input.lmb_released || input.focus_lost;
+        if let Some(drag) = &self.drag {
+            if bodies.rigid_body(drag.body).is_none() {
+                released = true;
+            } else if let Some(joint) = joint_constraints
+                .get_mut(drag.joint)
+                .and_then(|joint| joint.downcast_mut::<MouseConstraint<f32, DefaultBodyHandle>>())
+            {
+                joint.set_anchor_1(Point::new(input.cursor_x, input.cursor_y));
+            }
+        }
+        if released {
+            if let Some(drag) = self.drag.take() {
+                joint_constraints.remove(drag.joint);
+            }
+        }
+
+        // a press let go before it became a drag is a click
+        let mut tool_hits = Vec::new();
+        let clicked = match &self.press {
+            Some(press) => input.lmb_released && press.held < self.drag_hold_time,
+            None => false,
+        };
+        if input.lmb_released {
+            self.press = None;
+        }
+        if clicked {
+            // generate lmb events
+            let mut lmb_events = Vec::new();
+            let pkey = keys.cursor_emit_destroy_event_on_lmb_down;
+            compy.iterate_mut(pkey, keys.none, || {
+                lmb_events.push(cursor_xy);
+                false
+            });
+
+            // handle lmb events with the selected tool
+            let tool = if lmb_events.is_empty() {
+                None
+            } else {
+                self.tools.take()
+            };
+            match tool {
+                None => {}
+                Some(Tool::Hammer) => {
+                    // the block nearest the cursor's cell takes a hit
+                    let targets =
+                        cursor_targets(geometrical_world, colliders, self.cursor, cursor_xy)?;
+                    if let Some(&target) = targets.first() {
+                        let pkey = keys.take_cursor_damage + keys.hp + keys.physics_collider;
+                        compy.iterate_mut(
+                            pkey,
+                            keys.none,
+                            |hp: &mut HP, physics_collider: &PhysicsCollider| {
+                                if physics_collider.0 == target {
+                                    hp.0 = hp.0.saturating_sub(1);
+                                    let _ = audio_send.send(AudioEvent::Play(Sound::BlockHit, 1.));
+                                }
+                                false
+                            },
+                        );
+                    }
+                }
+                Some(tool @ Tool::RowClear) | Some(tool @ Tool::ColumnClear) => {
+                    let pkey = keys.take_cursor_damage + keys.hp + keys.physics_collider;
+                    let mut res = Ok(());
+                    compy.iterate_mut(
+                        pkey,
+                        keys.none,
+                        |hp: &mut HP, physics_collider: &PhysicsCollider| {
+                            res = res.and_then(|_| {
+                                let xy = get_collider(colliders, physics_collider.0)?
+                                    .position()
+                                    .translation
+                                    .vector;
+                                let in_line = match tool {
+                                    Tool::RowClear => (xy.y - temp_y).abs() < 16.,
+                                    _ => (xy.x - temp_x).abs() < 16.,
+                                };
+                                if in_line {
+                                    hp.0 = 0;
+                                }
+                                Ok(())
+                            });
+                            false
+                        },
+                    );
+                    res?;
+                    let _ = audio_send.send(AudioEvent::Play(Sound::BlockHit, 1.));
+                }
+                Some(Tool::Bomb) => {
+                    let center = Vector2::new(temp_x, temp_y);
+                    tool_hits = blast(
+                        geometrical_world,
+                        colliders,
+                        bodies,
+                        center,
+                        None,
+                        Self::BOMB,
+                    );
+                    let _ = audio_send.send(AudioEvent::Play(Sound::BlockLand, 1.));
+                }
+                Some(Tool::Freeze) => {
+                    // every block stops dead, and falling ones can land on them
+                    let mut frozen = Vec::new();
+                    let pkey = keys.physics_body + keys.take_cursor_damage;
+                    compy.iterate_mut(pkey, keys.none, |physics_body: &PhysicsBody| {
+                        if let Some(body) = bodies.rigid_body_mut(physics_body.0) {
+                            body.set_velocity(Velocity::zero());
+                            body.set_status(BodyStatus::Static);
+                            frozen.push(physics_body.0);
+                        }
+                        false
+                    });
+                    self.frozen.extend(frozen);
+                    self.freeze_timer = Self::FREEZE_TIME;
+                }
+                Some(Tool::GravityFlip) => {
+                    mechanical_world.gravity = Vector2::new(0., -self.gravity);
+                    self.flip_timer = Self::FLIP_TIME;
+                }
+            }
+        }
+
+        // if lmb was recently released, update on lmb release systems
+        if input.lmb_released {
+            let pkey = keys.set_uv_on_lmb_up + keys.sprite_uv;
+            compy.iterate_mut(
+                pkey,
+                keys.none,
+                |lmb_up_uv: &SetUVOnLMBUp, sprite_uv: &mut SpriteUV| {
+                    sprite_uv.0 = lmb_up_uv.0;
+                    sprite_uv.1 = lmb_up_uv.1;
+                    false
+                },
+            );
+        }
+
+        // map the sprite xy to the cursor position
+        let pkey = keys.cursor_snap_sprite_to_grid + keys.sprite_xy;
+        compy.iterate_mut(pkey, keys.none, |sprite_xy: &mut SpriteXY| {
+            sprite_xy.0 = temp_x - 16.;
+            sprite_xy.1 = temp_y - 16.;
+            false
+        });
+
+        // the selected tool's icon sits in the middle of the cursor
+        let icon = self.tools.selected().icon_uv();
+        let pkey = keys.cursor_tool_icon + keys.sprite_xy + keys.sprite_uv;
+        compy.iterate_mut(
+            pkey,
+            keys.none,
+            |sprite_xy: &mut SpriteXY, sprite_uv: &mut SpriteUV| {
+                match icon {
+                    Some(uv) => {
+                        *sprite_xy = SpriteXY(temp_x - 8., temp_y - 8.);
+                        *sprite_uv = SpriteUV(uv.0, uv.1);
+                    }
+                    None => *sprite_xy = SpriteXY(-99999., -99999.),
+                }
+                false
+            },
+        );
+
+        // puzzle mode, clear groups of resting blocks of one color
+        if mode == Mode::Puzzle {
+            self.chain_timer -= dt;
+            if self.chain_timer <= 0. {
+                self.chain = 0;
+            }
+
+            let matched = board.matches(puzzle.match_size as usize);
+
+            // matched blocks drop to 0 hp, and are removed with everything else below
+            if !matched.is_empty() {
+                let pkey = keys.hp + keys.physics_body + keys.block_shape;
+                let mut res = Ok(());
+                compy.iterate_mut(
+                    pkey,
+                    keys.none,
+                    |hp: &mut HP, physics_body: &PhysicsBody, shape: &BlockShape| {
+                        res = res.and_then(|_| {
+                            let body = get_rigid_body(bodies, physics_body.0)?;
+                            let cells = resting_cells(board, body, *shape);
+                            let aligned = cells.iter().filter(|&&(_, aligned)| aligned);
+                            if aligned
+                                .map(|(cell, _)| cell)
+                                .any(|cell| matched.contains(cell))
+                            {
+                                hp.0 = 0;
+                            }
+                            Ok(())
+                        });
+                        false
+                    },
+                );
+                res?;
+
+                // a match made while the last one is recent (blocks above falling into
+                // place) continues the chain, each link being worth more
+                self.chain += 1;
+                self.chain_timer = puzzle.chain_time;
+                self.score += matched.len() as u32 * 10 * self.chain;
+            }
+        }
+
+        // explosives at 0 hp blow up before they're removed, and may bring others to 0
+        //  Starts off with the damage from a bomb dropped this tick, if there was one.
+        let mut hit = tool_hits;
+        let mut exploded = Vec::new();
+        loop {
+            let pkey = keys.hp + keys.kill_upon_0_hp + keys.physics_collider;
+            compy.iterate_mut(
+                pkey,
+                keys.none,
+                |hp: &mut HP, collider: &PhysicsCollider| {
+                    for &(_, damage) in hit.iter().filter(|&&(h, _)| h == collider.0) {
+                        hp.0 = hp.0.saturating_sub(damage);
+                    }
+                    false
+                },
+            );
+
+            let mut blasts = Vec::new();
+            let pkey = keys.hp + keys.explosive + keys.physics_collider;
+            compy.iterate_mut(
+                pkey,
+                keys.none,
+                |hp: &HP, explosive: &Explosive, collider: &PhysicsCollider| {
+                    if hp.0 == 0 && !exploded.contains(&collider.0) {
+                        exploded.push(collider.0);
+                        blasts.push((collider.0, *explosive));
+                    }
+                    false
+                },
+            );
+            if blasts.is_empty() {
+                break;
+            }
+
+            hit = Vec::new();
+            for (source, explosive) in blasts {
+                let center = get_collider(colliders, source)?
+                    .position()
+                    .translation
+                    .vector;
+                hit.extend(blast(
+                    geometrical_world,
+                    colliders,
+                    bodies,
+                    center,
+                    Some(source),
+                    explosive,
+                ));
+                let _ = audio_send.send(AudioEvent::Play(Sound::BlockLand, 1.));
+            }
+        }
+
+        // destroy entities with <0 HP, blocks break up into pieces of each of their cells
+        let mut destroyed = 0;
+        let mut dead = Vec::new();
+        let mut broken = Vec::new();
+        let pkey = keys.hp + keys.kill_upon_0_hp + keys.physics_body + keys.physics_collider;
+        let mut res = Ok(());
+        compy.iterate_mut(
+            pkey + keys.block_kind + keys.block_shape + keys.sprite_uv,
+            keys.none,
+            |hp: &HP,
+             body: &PhysicsBody,
+             collider: &PhysicsCollider,
+             kind: &BlockKind,
+             shape: &BlockShape,
+             uv: &SpriteUV| {
+                if hp.0 != 0 {
+                    return false;
+                }
+                res = res.and_then(|_| {
+                    let rigid_body = get_rigid_body(bodies, body.0)?;
+                    let position = rigid_body.position();
+                    let velocity = rigid_body.velocity();
+                    for &(x, y) in shape.cells() {
+                        let xy = position * Point::new(x, y);
+                        let offset = xy.coords - position.translation.vector;
+                        let spin = Vector2::new(-offset.y, offset.x) * velocity.angular;
+                        broken.push(Piece {
+                            kind: *kind,
+                            xy: xy.coords,
+                            rotation: position.rotation.angle(),
+                            velocity: velocity.linear + spin,
+                            angular_velocity: velocity.angular,
+                            uv: (uv.0, uv.1),
+                            wh: (32., 32.),
+                        });
+                    }
+                    Ok(())
+                });
+                destroyed += 1;
+                dead.push((body.0, collider.0));
+                let _ = audio_send.send(AudioEvent::Play(Sound::BlockDestroyed, 1.));
+                true
+            },
+        );
+        res?;
+
+        // fragments break up further, but don't count as blocks destroyed
+        let pkey = keys.hp + keys.fragment + keys.sprite_uv;
+        let mut res = Ok(());
+        compy.iterate_mut(
+            pkey + keys.physics_body + keys.physics_collider,
+            keys.none,
+            |hp: &HP,
+             fragment: &Fragment,
+             uv: &SpriteUV,
+             body: &PhysicsBody,
+             collider: &PhysicsCollider| {
+                if hp.0 != 0 {
+                    return false;
+                }
+                res = res.and_then(|_| {
+                    let rigid_body = get_rigid_body(bodies, body.0)?;
+                    let position = rigid_body.position();
+                    let velocity = rigid_body.velocity();
+                    broken.push(Piece {
+                        kind: fragment.kind,
+                        xy: position.translation.vector,
+                        rotation: position.rotation.angle(),
+                        velocity: velocity.linear,
+                        angular_velocity: velocity.angular,
+                        uv: (uv.0, uv.1),
+                        wh: fragment.wh,
+                    });
+                    Ok(())
+                });
+                dead.push((body.0, collider.0));
+                let _ = audio_send.send(AudioEvent::Play(Sound::BlockDestroyed, 0.5));
+                true
+            },
+        );
+        res?;
+        self.blocks_destroyed += destroyed;
+        self.garbage_out += destroyed;
+        if mode != Mode::Puzzle {
+            self.score += destroyed * 10;
+        }
+
+        // scoring earns tool charges
+        if self.tools.award(self.score, &mut self.rng).is_some() {
+            let _ = audio_send.send(AudioEvent::Play(Sound::BlockSpawn, 1.));
+        }
+
+        // expire short lived entities (debris)
+        let pkey = keys.kill_after + keys.physics_body + keys.physics_collider;
+        compy.iterate_mut(
+            pkey,
+            keys.none,
+            |kill_after: &mut KillAfter, body: &PhysicsBody, collider: &PhysicsCollider| {
+                kill_after.0 -= dt;
+                if kill_after.0 <= 0. {
+                    dead.push((body.0, collider.0));
+                    return true;
+                }
+                false
+            },
+        );
+
+        // cleanup phase, dead entities take their physics objects with them
+        for (body, collider) in dead {
+            colliders.remove(collider);
+            bodies.remove(body);
+        }
+
+        // what was broken is replaced by its pieces
+        for piece in broken.iter() {
+            for piece in crate::fracture::split(piece, fracture.get(piece.kind)) {
+                crate::components::create_fragment(
+                    &piece, block, materials, compy, bodies, colliders,
+                );
+            }
+        }
+
+        // update ecs
+        compy.update();
+
+        // print stats
+        if print_stats {
+            println!("ft: {:?}", get_microseconds_as_u64() - ft_start);
+            compy.print_stats();
+        }
+
+        Ok(())
+    }
+
+    // pushes the sprites and debug shapes of this round into the render state
+    // origin is where the top left of the well goes on screen
+    pub fn render(
+        &mut self,
+        render_state: &mut RenderState,
+        origin: (f32, f32),
+    ) -> Result<(), GameErr> {
+        let (ox, oy) = origin;
+        let Game {
+            bodies,
+            colliders,
+            compy,
+            keys,
+            ..
+        } = self;
+        let keys = *keys;
+
+        // map the sprites position to the physics position
+        let pkey = keys.sprite_xy + keys.sprite_r + keys.physics_body + keys.sync_sprite_to_physics;
+        let mut res = Ok(());
+        compy.iterate_mut(
+            pkey,
+            keys.none,
+            |sprite_xy: &mut SpriteXY, sprite_r: &mut SpriteR, phys: &PhysicsBody| {
+                res = res.and_then(|_| {
+                    let pos = get_rigid_body(bodies, phys.0)?.position();
+                    sprite_xy.0 = pos.translation.vector.x;
+                    sprite_xy.1 = pos.translation.vector.y;
+                    let rot = pos.rotation.into_inner();
+                    sprite_r.0 = rot.im.atan2(rot.re);
+                    Ok(())
+                });
+                false
+            },
+        );
+        res?;
+
+        // pull some data out of the ECS for the renderer
+        compy.iterate_mut(keys.sprite_id, keys.none, |sprite_id: &SpriteId| {
+            render_state.sprite_ids.push(sprite_id.0);
+            false
+        });
+
+        compy.iterate_mut(keys.sprite_xy, keys.none, |sprite_xy: &SpriteXY| {
+            render_state
+                .sprite_xys
+                .push((sprite_xy.0 + ox, sprite_xy.1 + oy));
+            false
+        });
+
+        compy.iterate_mut(keys.sprite_uv, keys.none, |sprite_uv: &SpriteUV| {
+            render_state.sprite_uvs.push((sprite_uv.0, sprite_uv.1));
+            false
+        });
+
+        compy.iterate_mut(keys.sprite_wh, keys.none, |sprite_wh: &SpriteWH| {
+            render_state.sprite_whs.push((sprite_wh.0, sprite_wh.1));
+            false
+        });
+
+        compy.iterate_mut(keys.sprite_r, keys.none, |sprite_r: &SpriteR| {
+            render_state
+                .sprite_rghs
+                .push((sprite_r.0, sprite_r.1, sprite_r.2));
+            false
+        });
+
+        // blocks of more than one cell get a sprite for each of the others
+        let pkey = keys.sprite_id + keys.sprite_xy + keys.sprite_uv + keys.sprite_r;
+        compy.iterate_mut(
+            pkey + keys.block_shape,
+            keys.none,
+            |sprite_id: &SpriteId,
+             sprite_xy: &SpriteXY,
+             sprite_uv: &SpriteUV,
+             sprite_r: &SpriteR,
+             shape: &BlockShape| {
+                for (i, &(x, y)) in shape.cells().iter().enumerate().skip(1) {
+                    render_state.sprite_ids.push(sprite_id.0 + i as u32);
+                    render_state
+                        .sprite_xys
+                        .push((sprite_xy.0 + ox, sprite_xy.1 + oy));
+                    render_state.sprite_uvs.push((sprite_uv.0, sprite_uv.1));
+                    render_state.sprite_whs.push((32., 32.));
+                    render_state
+                        .sprite_rghs
+                        .push((sprite_r.0, sprite_r.1 + x, sprite_r.2 + y));
+                }
+                false
+            },
+        );
+
+        // generate wireframe data for the renderer
+        let mut wireframes = Vec::new();
+        let mut res = Ok(());
+        compy.iterate_mut(
+            keys.physics_collider,
+            keys.none,
+            |phys: &PhysicsCollider| {
+                res = res.and_then(|_| {
+                    let t = get_collider(colliders, phys.0)?;
+                    let iso = Isometry::translation(ox, oy) * t.position();
+                    outlines(&**t.shape(), &iso, &mut wireframes);
+                    Ok(())
+                });
+                false
+            },
+        );
+        res?;
+
+        // generate body data for the renderer
+        let mut rigid_bodies = Vec::new();
+        let mut res = Ok(());
+        compy.iterate_mut(
+            keys.physics_body,
+            keys.none,
+            |physics_body: &PhysicsBody| {
+                res = res.and_then(|_| {
+                    let pos = get_rigid_body(bodies, physics_body.0)?
+                        .position()
+                        .translation
+                        .vector;
+                    rigid_bodies.push((pos.x + ox, pos.y + oy));
+                    Ok(())
+                });
+                false
+            },
+        );
+        res?;
+
+        render_state
+            .wireframes
+            .get_or_insert_with(Vec::new)
+            .extend(wireframes);
+        render_state
+            .rigid_bodies
+            .get_or_insert_with(Vec::new)
+            .extend(rigid_bodies);
+
+        // hud
+        render_state.push_text((ox + 4., oy + 2.), &format!("SCORE {}", self.score));
+        let tool = self.tools.selected();
+        let text = match self.tools.charges(tool) {
+            Some(charges) => format!("{} x{}", tool.name(), charges),
+            None => tool.name().to_string(),
+        };
+        let x = self.camw - text.len() as f32 * 8. - 4.;
+        render_state.push_text((ox + x, oy + 2.), &text);
+        if self.chain > 1 {
+            render_state.push_text((ox + 4., oy + 18.), &format!("CHAIN x{}", self.chain));
+        }
+        if self.garbage_queue > 0 {
+            let text = format!("INCOMING {}", self.garbage_queue);
+            render_state.push_text((ox + 4., oy + 34.), &text);
+        }
+
+        // the upcoming blocks in a panel over the right hand wall, and a sliver of the next
+        // one at the top of the column it'll drop in
+        if self.preview > 0 {
+            let x = ox + self.camw - 48.;
+            let h = 22. + self.preview as f32 * 36.;
+            render_state.push_panel((x, oy + 18.), (40., h));
+            render_state.push_text((x + 4., oy + 22.), "NEXT");
+            for (i, spawn) in self.spawns.upcoming().take(self.preview).enumerate() {
+                let y = oy + 38. + i as f32 * 36.;
+                render_state.push_sprite((x + 4., y), spawn.kind.uv(), (32., 32.));
+            }
+        }
+        if let Some(next) = self.spawns.upcoming().next() {
+            let (left, right) = next.shape.reach();
+            for column in next.column - left..=next.column + right {
+                let x = ox + 64. + column as f32 * 32.;
+                render_state.push_sprite((x, oy), next.kind.uv(), (32., 4.));
+            }
+        }
+
+        Ok(())
+    }
+}
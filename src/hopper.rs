@@ -0,0 +1,106 @@
+use crate::{
+    components::{Group, Hopper},
+    input::Input,
+};
+use glutin::VirtualKeyCode;
+use nphysics2d::{
+    object::{Body, DefaultBodySet, DefaultColliderHandle, DefaultColliderSet, RigidBody},
+    world::DefaultGeometricalWorld,
+};
+
+const RUN_SPEED: f32 = 96.;
+const JUMP_SPEED: f32 = 200.;
+const WALL_JUMP_SPEED: f32 = 120.; // sideways, away from the wall
+const WALL_JUMP_TIME: f32 = 0.2; // seconds before running steers again after a wall jump
+const CRUSH_SPEED: f32 = 8.; // a block falling faster than this onto a grounded hopper kills it
+
+// idle and running animations, as uvs of 32x32 frames
+const IDLE: [(f32, f32); 4] = [(0., 176.), (32., 176.), (64., 176.), (96., 176.)];
+const RUN: [(f32, f32); 2] = [(128., 176.), (160., 176.)];
+const FRAME_TIME: f32 = 0.15;
+
+// What the hopper is up against, from its contacts
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Touching {
+    pub ground: bool,
+    pub wall: f32, // -1 for a wall to the left, 1 to the right, 0 for none
+    pub crushed: bool,
+}
+
+// reads the contacts the hopper's collider had during the last step
+pub fn touching(
+    geometrical_world: &DefaultGeometricalWorld<f32>,
+    colliders: &DefaultColliderSet<f32>,
+    bodies: &DefaultBodySet<f32>,
+    hopper: DefaultColliderHandle,
+) -> Touching {
+    let mut touching = Touching::default();
+    let contacts = match geometrical_world.contacts_with(colliders, hopper, true) {
+        Some(contacts) => contacts,
+        None => return touching,
+    };
+    for (h1, c1, _, c2, _, manifold) in contacts {
+        // normals point away from the first collider, turn them to point away from the hopper
+        let (other, sign) = if h1 == hopper { (c2, 1.) } else { (c1, -1.) };
+        let falling = bodies
+            .rigid_body(other.body())
+            .map(|body| body.velocity().linear.y > CRUSH_SPEED)
+            .unwrap_or(false);
+        let block = Group::Blocks.is_member(other.collision_groups());
+        for contact in manifold.contacts() {
+            let normal = contact.contact.normal.into_inner() * sign;
+            if normal.y > 0.7 {
+                touching.ground = true;
+            } else if normal.y < -0.7 {
+                touching.crushed |= block && falling;
+            } else if normal.x.abs() > 0.7 {
+                touching.wall = normal.x.signum();
+            }
+        }
+    }
+    // something coming down is only deadly with something underneath
+    touching.crushed &= touching.ground;
+    touching
+}
+
+// runs and jumps from the keyboard, returns the animation frame to show
+pub fn control(
+    hopper: &mut Hopper,
+    body: &mut RigidBody<f32>,
+    touching: Touching,
+    input: &Input,
+    dt: f32,
+) -> (f32, f32) {
+    let held = |keys: &[VirtualKeyCode]| keys.iter().any(|&key| input.key_held(key));
+    let pressed = |keys: &[VirtualKeyCode]| keys.iter().any(|&key| input.key_pressed(key));
+    let left = held(&[VirtualKeyCode::Left, VirtualKeyCode::A]);
+    let right = held(&[VirtualKeyCode::Right, VirtualKeyCode::D]);
+    let jump = pressed(&[VirtualKeyCode::Up, VirtualKeyCode::W, VirtualKeyCode::Space]);
+
+    let mut velocity = body.velocity().linear;
+    hopper.wall_jump = (hopper.wall_jump - dt).max(0.);
+    if hopper.wall_jump == 0. {
+        velocity.x = (right as i32 - left as i32) as f32 * RUN_SPEED;
+    }
+    if jump && touching.ground {
+        velocity.y = -JUMP_SPEED;
+    } else if jump && touching.wall != 0. {
+        velocity.y = -JUMP_SPEED;
+        velocity.x = -touching.wall * WALL_JUMP_SPEED;
+        hopper.wall_jump = WALL_JUMP_TIME;
+    }
+    if velocity != body.velocity().linear {
+        body.set_linear_velocity(velocity);
+        body.activate();
+    }
+
+    hopper.anim += dt;
+    let frame = (hopper.anim / FRAME_TIME) as usize;
+    if !touching.ground {
+        RUN[1]
+    } else if velocity.x != 0. {
+        RUN[frame % RUN.len()]
+    } else {
+        IDLE[frame % IDLE.len()]
+    }
+}
@@ -0,0 +1,46 @@
+use glutin::VirtualKeyCode;
+
+// Input gathered by the update thread for a single tick, cursor in game coordinates
+#[derive(Clone, Default)]
+pub struct Input {
+    pub cursor_x: f32,
+    pub cursor_y: f32,
+    pub cursor_moved: bool,
+    pub lmb_pressed: bool,
+    pub lmb_released: bool,
+    pub scroll: i32, // wheel notches, positive is down
+    pub keys_pressed: Vec<VirtualKeyCode>,
+    pub keys_held: Vec<VirtualKeyCode>, // persists between ticks
+    pub focus_lost: bool,
+}
+
+impl Input {
+    pub fn key_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.keys_pressed.contains(&key)
+    }
+
+    pub fn key_held(&self, key: VirtualKeyCode) -> bool {
+        self.keys_held.contains(&key)
+    }
+
+    pub fn press(&mut self, key: VirtualKeyCode) {
+        self.keys_pressed.push(key);
+        if !self.key_held(key) {
+            self.keys_held.push(key);
+        }
+    }
+
+    pub fn release(&mut self, key: VirtualKeyCode) {
+        self.keys_held.retain(|&k| k != key);
+    }
+
+    // clears everything but the cursor position and held keys, which persist between ticks
+    pub fn clear(&mut self) {
+        self.cursor_moved = false;
+        self.lmb_pressed = false;
+        self.lmb_released = false;
+        self.scroll = 0;
+        self.keys_pressed.clear();
+        self.focus_lost = false;
+    }
+}
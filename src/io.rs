@@ -1,29 +1,128 @@
-use bincode::{deserialize, serialize};
-use serde::{de::DeserializeOwned, Serialize};
-use std::fs::File;
+use bincode::serialize;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
 
 lazy_static! {
-    static ref ROOT: PathBuf = { PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()) };
+    static ref ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
 }
+static ROOT_SET: AtomicBool = AtomicBool::new(false); // by set_root
 
+// the asset directory (textures/, shaders/, audio/), located on first use unless set_root
+// was called before
 pub fn get_root() -> PathBuf {
-    ROOT.clone()
+    if let Some(root) = ROOT.read().unwrap().as_ref() {
+        return root.clone();
+    }
+    ROOT.write()
+        .unwrap()
+        .get_or_insert_with(locate_root)
+        .clone()
 }
 
-#[allow(dead_code)]
-pub fn save_to_file<T: Serialize>(t: T, filename: &Path) {
-    let mut file = File::create(ROOT.join(filename)).expect("Could not open file");
-    let serialized_data: Vec<u8> = serialize(&t).unwrap();
-    file.write(&serialized_data).unwrap();
+// overrides the asset directory, eg. from the command line
+pub fn set_root(dir: PathBuf) {
+    *ROOT.write().unwrap() = Some(dir);
+    ROOT_SET.store(true, Ordering::SeqCst);
 }
 
-#[allow(dead_code)]
-pub fn load_from_file<T: DeserializeOwned>(filename: &Path) -> T {
-    let mut file = File::open(ROOT.join(filename)).expect("Could not open file");
-    let mut serialized_data = Vec::<u8>::new();
-    file.read_to_end(&mut serialized_data).unwrap();
-    let data = deserialize(&serialized_data).unwrap();
-    return data;
+// whether the asset directory was named (--assets or $BLOCKHOP_ASSETS) rather than searched
+// for, the embedded assets are only used when it wasn't
+#[cfg(feature = "embed-assets")]
+pub fn root_is_named() -> bool {
+    ROOT_SET.load(Ordering::SeqCst) || std::env::var_os("BLOCKHOP_ASSETS").is_some()
+}
+
+// a directory is the asset root if it has a packed archive, or the textures and shaders
+fn is_root(dir: &Path) -> bool {
+    dir.join(crate::archive::ARCHIVE_FILE).is_file()
+        || (dir.join("textures").is_dir() && dir.join("shaders").is_dir())
+}
+
+// $BLOCKHOP_ASSETS, then next to the executable (or up to two directories above it, for
+// target/<profile>/), then the working directory, then the source tree the binary was built
+// from
+fn locate_root() -> PathBuf {
+    if let Some(dir) = std::env::var_os("BLOCKHOP_ASSETS") {
+        return PathBuf::from(dir);
+    }
+
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    let candidates = exe_dir
+        .iter()
+        .flat_map(|dir| dir.ancestors().take(3).map(Path::to_path_buf))
+        .chain(std::env::current_dir().ok())
+        .chain(Some(PathBuf::from(env!("CARGO_MANIFEST_DIR"))));
+    for dir in candidates {
+        if is_root(&dir) {
+            return dir;
+        }
+    }
+
+    println!("Could not find the asset directory, set BLOCKHOP_ASSETS or pass --assets");
+    PathBuf::from(".")
+}
+
+// where saved data (high scores) lives: $BLOCKHOP_DATA_DIR, or blockhop/ in the platform's
+// user data directory, or the root as a last resort
+pub fn get_data_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("BLOCKHOP_DATA_DIR") {
+        return PathBuf::from(dir);
+    }
+    match dirs::data_dir() {
+        Some(dir) => dir.join("blockhop"),
+        None => get_root(),
+    }
+}
+
+#[derive(Debug)]
+pub enum IoErr {
+    Open(PathBuf, std::io::Error),
+    Read(PathBuf, std::io::Error),
+    Write(PathBuf, std::io::Error),
+    Corrupt(PathBuf, bincode::Error),
+}
+
+impl fmt::Display for IoErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoErr::Open(path, e) => write!(f, "could not open {:?}: {}", path, e),
+            IoErr::Read(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            IoErr::Write(path, e) => write!(f, "could not write {:?}: {}", path, e),
+            IoErr::Corrupt(path, e) => write!(f, "{:?} is corrupt: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for IoErr {}
+
+// writes to a temporary file first, so a crash mid-save can't leave a truncated file behind
+pub fn save_to_file<T: Serialize>(t: T, path: &Path) -> Result<(), IoErr> {
+    let serialized_data: Vec<u8> =
+        serialize(&t).map_err(|e| IoErr::Corrupt(path.to_path_buf(), e))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| IoErr::Open(dir.to_path_buf(), e))?;
+    }
+    let temp = path.with_extension("tmp");
+    let mut file = File::create(&temp).map_err(|e| IoErr::Open(temp.clone(), e))?;
+    file.write_all(&serialized_data)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| IoErr::Write(temp.clone(), e))?;
+    fs::rename(&temp, path).map_err(|e| IoErr::Write(path.to_path_buf(), e))
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<u8>, IoErr> {
+    let mut file = File::open(path).map_err(|e| IoErr::Open(path.to_path_buf(), e))?;
+    let mut data = Vec::<u8>::new();
+    file.read_to_end(&mut data)
+        .map_err(|e| IoErr::Read(path.to_path_buf(), e))?;
+    Ok(data)
 }
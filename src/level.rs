@@ -0,0 +1,95 @@
+use crate::{
+    archive::{ArchiveErr, Assets},
+    board::COLUMNS,
+    components::{BlockKind, BlockShape, BLOCK_COLOR_UVS},
+    settings::Material,
+    spawn::{RandomizerKind, ScriptedSpawn},
+};
+use serde_derive::Deserialize;
+use std::fmt;
+
+pub const DEFAULT_LEVEL: &str = "levels/default.toml";
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LevelSprite {
+    pub xy: (f32, f32),
+    pub uv: (f32, f32),
+    pub wh: (f32, f32),
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LevelWall {
+    pub xy: (f32, f32),
+    pub wh: (f32, f32),
+    pub material: Option<Material>, // instead of materials.wall from the settings
+}
+
+// How blocks are spawned in a level, the game mode decides when the randomizer is left out
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LevelSpawns {
+    pub randomizer: Option<RandomizerKind>,
+    #[serde(default)]
+    pub script: Vec<ScriptedSpawn>, // for the scripted randomizer
+    #[serde(default)]
+    pub shapes: Vec<BlockShape>, // what blocks are rolled as, all squares when left out
+}
+
+// The layout of a well, read from a toml file in levels/
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Level {
+    pub height: f32, // the screen height the coordinates are for
+    #[serde(default)]
+    pub sprites: Vec<LevelSprite>,
+    #[serde(default)]
+    pub walls: Vec<LevelWall>,
+    #[serde(default)]
+    pub spawns: LevelSpawns,
+}
+
+#[derive(Debug)]
+pub enum LevelErr {
+    Asset(ArchiveErr),
+    Parse(String, toml::de::Error),
+    Invalid(String, String), // level name, what's wrong with it
+}
+
+impl fmt::Display for LevelErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelErr::Asset(e) => write!(f, "could not load level: {}", e),
+            LevelErr::Parse(name, e) => write!(f, "{:?}: {}", name, e),
+            LevelErr::Invalid(name, e) => write!(f, "{:?}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for LevelErr {}
+
+impl Level {
+    pub fn load(assets: &Assets, name: &str) -> Result<Level, LevelErr> {
+        let bytes = assets.read(name).map_err(LevelErr::Asset)?;
+        let level: Level =
+            toml::from_slice(&bytes).map_err(|e| LevelErr::Parse(name.to_string(), e))?;
+
+        let invalid = |e: String| Err(LevelErr::Invalid(name.to_string(), e));
+        let spawns = &level.spawns;
+        if spawns.randomizer == Some(RandomizerKind::Scripted) && spawns.script.is_empty() {
+            return invalid("the scripted randomizer needs a script".to_string());
+        }
+        for (i, spawn) in spawns.script.iter().enumerate() {
+            if spawn.column >= COLUMNS {
+                return invalid(format!("script step {} has no column {}", i, spawn.column));
+            }
+            if let Some(BlockKind::Colored(color)) = spawn.kind {
+                if color as usize >= BLOCK_COLOR_UVS.len() {
+                    return invalid(format!("script step {} has no color {}", i, color));
+                }
+            }
+        }
+        Ok(level)
+    }
+}
@@ -3,56 +3,184 @@ extern crate lazy_static;
 extern crate bincode;
 extern crate compy;
 extern crate crossbeam_channel;
+extern crate dirs;
 extern crate ezgl;
+extern crate flate2;
 extern crate gl;
 extern crate glutin;
+extern crate hound;
 extern crate nalgebra;
 extern crate ncollide2d;
 extern crate nphysics2d;
 extern crate rand;
+extern crate rodio;
 extern crate serde_derive;
+extern crate toml;
 
+mod archive;
+mod audio;
+mod board;
 mod camera;
 mod components;
+#[cfg(feature = "embed-assets")]
+mod embed;
+mod error;
+mod fracture;
+mod game;
+mod hopper;
+mod input;
 mod io;
+mod level;
+mod menu;
+mod music;
+mod net;
 mod render;
+mod scores;
+mod settings;
+mod shutdown;
+mod spawn;
+mod stream;
 mod time;
+mod tools;
 mod update;
 
+use crate::{
+    error::join,
+    render::Snapshots,
+    settings::{Args, Settings},
+    shutdown::Shutdown,
+    update::UpdateErr,
+};
 use glutin::{dpi::LogicalSize, ContextBuilder, EventsLoop, GlWindow, WindowBuilder};
 
 fn main() {
-    let camw = 352f32;
-    let camh = 176f32;
+    // command line, the asset directory has to be known before anything is loaded
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => exit_with_error(e),
+    };
+    if let Some(dir) = &args.assets {
+        crate::io::set_root(dir.clone());
+    }
+
+    // blockhop --pack <path> builds an asset archive instead of running the game
+    if let Some(out) = &args.pack {
+        let root = crate::io::get_root();
+        match crate::archive::pack(&root, out) {
+            Ok(count) => println!("Packed {} assets from {:?} into {:?}", count, root, out),
+            Err(e) => exit_with_error(e),
+        }
+        return;
+    }
+
+    // settings, from settings.toml and the command line
+    let settings = match Settings::load(&args) {
+        Ok(settings) => settings,
+        Err(e) => exit_with_error(e),
+    };
+    let camw = settings.camera.width;
+    let camh = settings.camera.height;
+    let volume = settings.volume;
 
     // window, loop and context
     let mut events_loop = EventsLoop::new();
     let window_builder = WindowBuilder::new()
         .with_title("Block Hop")
-        .with_dimensions(LogicalSize::new(camw as f64 * 3., camh as f64 * 3.));
-    let context = ContextBuilder::new().with_vsync(true);
-    let window = GlWindow::new(window_builder, context, &events_loop).unwrap();
+        .with_dimensions(LogicalSize::new(
+            camw as f64 * settings.window.scale as f64,
+            camh as f64 * settings.window.scale as f64,
+        ));
+    let context = ContextBuilder::new().with_vsync(settings.window.vsync);
+    let window = match GlWindow::new(window_builder, context, &events_loop) {
+        Ok(window) => window,
+        Err(e) => exit_with_error(e),
+    };
 
-    // render state send/recv pa`
-    let (render_send, render_recv) = crossbeam_channel::bounded(0);
+    // the latest render states, shared between the update and draw threads
+    let snapshots = Snapshots::default();
 
     // input send/recv pair
     let (input_send, input_recv) = crossbeam_channel::unbounded();
 
-    // update thread
-    let update =
-        std::thread::spawn(move || crate::update::update(camw, camh, render_send, input_recv));
+    // audio event send/recv pair
+    let (audio_send, audio_recv) = crossbeam_channel::unbounded();
+
+    // shutdown flag, set when any of the threads below stops
+    let shutdown = Shutdown::new(events_loop.create_proxy());
+
+    // update thread, or with --watch a stream played back in its place
+    let update = {
+        let snapshots = snapshots.clone();
+        let shutdown = shutdown.clone();
+        std::thread::spawn(move || {
+            let _guard = shutdown.guard();
+            match args.watch.clone() {
+                Some(source) => crate::stream::watch(
+                    source,
+                    snapshots,
+                    input_recv,
+                    audio_send,
+                    shutdown.clone(),
+                )
+                .map_err(UpdateErr::Stream),
+                None => crate::update::update(
+                    settings,
+                    args,
+                    snapshots,
+                    input_recv,
+                    audio_send,
+                    shutdown.clone(),
+                ),
+            }
+        })
+    };
+
+    // audio thread
+    let audio = std::thread::spawn(move || {
+        let backend = crate::audio::open_backend();
+        crate::audio::audio(backend, volume, audio_recv)
+    });
 
     // draw thread
-    let render = std::thread::spawn(move || crate::render::render(camw, camh, window, render_recv));
+    let render = {
+        let shutdown = shutdown.clone();
+        std::thread::spawn(move || {
+            let _guard = shutdown.guard();
+            crate::render::render(window, snapshots, shutdown.clone())
+        })
+    };
 
     // input "thread"
-    events_loop.run_forever(|event| match input_send.send(event) {
-        Ok(_) => glutin::ControlFlow::Continue,
-        Err(_) => glutin::ControlFlow::Break,
+    //  Close requests are forwarded like any other event, the update thread decides when to
+    //  stop. The loop ends once a shutdown is requested (which also wakes it up).
+    events_loop.run_forever(|event| {
+        if shutdown.is_requested() {
+            return glutin::ControlFlow::Break;
+        }
+        match input_send.send(event) {
+            Ok(_) => glutin::ControlFlow::Continue,
+            Err(_) => glutin::ControlFlow::Break,
+        }
     });
 
-    // wait
-    update.join().unwrap().unwrap();
-    render.join().unwrap().unwrap();
+    // wait, then report whatever went wrong in any thread
+    let errors: Vec<_> = vec![
+        join("update", update),
+        join("render", render),
+        join("audio", audio),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect();
+    for e in &errors {
+        eprintln!("blockhop: {}", e);
+    }
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn exit_with_error<E: std::fmt::Display>(e: E) -> ! {
+    eprintln!("blockhop: {}", e);
+    std::process::exit(1);
 }
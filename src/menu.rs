@@ -0,0 +1,661 @@
+use crate::{
+    audio::AudioEvent,
+    game::{versus_tick, Game, GameErr, Mode},
+    input::Input,
+    level::Level,
+    music::Track,
+    net::{NetConfig, NetMatch},
+    render::RenderState,
+    scores::{HighScores, Score},
+    settings::Settings,
+};
+use crossbeam_channel::Sender;
+use glutin::VirtualKeyCode;
+use std::path::PathBuf;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScreenKind {
+    Title,
+    Options,
+    Gameplay,
+    Pause,
+    GameOver,
+}
+
+impl ScreenKind {
+    // overlays are drawn on top of the screen below them
+    fn is_overlay(self) -> bool {
+        match self {
+            ScreenKind::Pause | ScreenKind::GameOver => true,
+            _ => false,
+        }
+    }
+}
+
+// What selecting a menu item does
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Action {
+    Play,
+    Puzzle,
+    Hop,
+    Versus,
+    Online,
+    Options,
+    Quit,
+    Resume,
+    QuitToTitle,
+    Retry,
+    Back,
+    MasterVolume,
+    SfxVolume,
+    MusicVolume,
+    WindowScale,
+    SwapMouseButtons,
+    PauseOnFocusLoss,
+}
+
+struct Screen {
+    kind: ScreenKind,
+    selected: usize,
+}
+
+// The screen stack, the top screen receives input
+pub struct Screens {
+    camw: f32,
+    camh: f32,
+    stack: Vec<Screen>,
+    game: Option<Game>,
+    rival: Option<Game>, // the right hand well in versus, played from the keyboard
+    keyboard_cell: (i32, i32), // the keyboard player's cursor, as a column and a row
+    mode: Mode,          // of the current or last round
+    versus: bool,
+    net_config: Option<NetConfig>, // from the command line, online play is offered with one
+    net: Option<NetMatch>,
+    level: Level,
+    pub settings: Settings,
+    saved: Settings, // the settings without the command line overrides, changed along with them
+    settings_path: PathBuf,
+    settings_changed: bool,
+    high_scores: HighScores,  // for mode
+    last_rank: Option<usize>, // where the last round placed in the high scores
+    audio_send: Sender<AudioEvent>,
+    quit: bool,
+}
+
+impl Screens {
+    const ITEM_SPACING: f32 = 16.;
+    const SCORES_SHOWN: usize = 5;
+
+    pub fn new(
+        settings: Settings,
+        settings_path: PathBuf,
+        level: Level,
+        net_config: Option<NetConfig>,
+        audio_send: Sender<AudioEvent>,
+    ) -> Screens {
+        let saved = Settings::load_saved(&settings_path).unwrap_or_else(|_| settings.clone());
+        let mut screens = Screens {
+            camw: settings.camera.width,
+            camh: settings.camera.height,
+            stack: Vec::new(),
+            game: None,
+            rival: None,
+            keyboard_cell: (3, 0),
+            mode: Mode::Classic,
+            versus: false,
+            net_config,
+            net: None,
+            level,
+            settings,
+            saved,
+            settings_path,
+            settings_changed: false,
+            high_scores: HighScores::load(Mode::Classic.scores_file()),
+            last_rank: None,
+            audio_send,
+            quit: false,
+        };
+        screens.push(ScreenKind::Title);
+        screens
+    }
+
+    // true once the player chose to quit
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    // saves anything that would otherwise be lost, called once when the game closes
+    pub fn shutdown(&mut self) {
+        self.save_settings();
+        self.high_scores.save();
+    }
+
+    // writes the settings back if they were changed in the options menu, without the command
+    // line overrides
+    fn save_settings(&mut self) {
+        if !self.settings_changed {
+            return;
+        }
+        match self.saved.save(&self.settings_path) {
+            Ok(()) => self.settings_changed = false,
+            Err(e) => println!("Could not save settings: {}", e),
+        }
+    }
+
+    // the size of the visible area, twice as wide while two wells are side by side
+    pub fn camera(&self) -> (f32, f32) {
+        if self.rival.is_some() || self.net.is_some() {
+            (self.camw * 2., self.camh)
+        } else {
+            (self.camw, self.camh)
+        }
+    }
+
+    fn top(&self) -> ScreenKind {
+        self.stack.last().unwrap().kind
+    }
+
+    fn push(&mut self, kind: ScreenKind) {
+        self.stack.push(Screen { kind, selected: 0 });
+        self.on_enter(kind);
+    }
+
+    fn pop(&mut self) {
+        self.stack.pop();
+        if self.stack.is_empty() {
+            self.quit = true;
+            return;
+        }
+        let kind = self.top();
+        self.on_enter(kind);
+    }
+
+    // pops screens until kind is on top
+    fn pop_to(&mut self, kind: ScreenKind) {
+        while self.stack.len() > 1 && self.top() != kind {
+            self.stack.pop();
+        }
+        self.on_enter(kind);
+    }
+
+    fn on_enter(&mut self, kind: ScreenKind) {
+        let track = match kind {
+            ScreenKind::Title | ScreenKind::Options => Track::Menu,
+            ScreenKind::Gameplay | ScreenKind::Pause => Track::Gameplay,
+            ScreenKind::GameOver => Track::Silence,
+        };
+        let _ = self.audio_send.send(AudioEvent::Music(track));
+        if kind == ScreenKind::Title {
+            self.game = None;
+            self.rival = None;
+            self.net = None;
+        }
+    }
+
+    fn start_game(&mut self, mode: Mode) {
+        if mode != self.mode {
+            self.high_scores.save();
+            self.high_scores = HighScores::load(mode.scores_file());
+            self.mode = mode;
+        }
+        self.game = Some(Game::new(&self.settings, &self.level, mode, rand::random()));
+        self.versus = false;
+        self.push(ScreenKind::Gameplay);
+    }
+
+    // two classic wells side by side, the mouse playing the left and the keyboard the right
+    fn start_versus(&mut self) {
+        let seed = rand::random();
+        self.game = Some(Game::new(&self.settings, &self.level, Mode::Classic, seed));
+        self.rival = Some(Game::new(&self.settings, &self.level, Mode::Classic, seed));
+        self.keyboard_cell = (3, 0);
+        self.versus = true;
+        self.push(ScreenKind::Gameplay);
+    }
+
+    // versus against another player over the network
+    fn start_online(&mut self) {
+        let config = match &self.net_config {
+            Some(config) => config,
+            None => return,
+        };
+        match NetMatch::start(config, &self.settings, &self.level) {
+            Ok(net) => {
+                self.net = Some(net);
+                self.keyboard_cell = (3, 0);
+                self.versus = true;
+                self.push(ScreenKind::Gameplay);
+            }
+            Err(e) => println!("Could not start an online game: {}", e),
+        }
+    }
+
+    // the keyboard player's input as if it came from a mouse: arrows or WASD move the cursor
+    // a cell, space or enter clicks, Q and E change tools
+    fn keyboard_input(&mut self, input: &Input) -> Input {
+        let mut click = false;
+        let mut scroll = 0;
+        let (mut column, mut row) = self.keyboard_cell;
+        for &key in &input.keys_pressed {
+            match key {
+                VirtualKeyCode::Left | VirtualKeyCode::A => column -= 1,
+                VirtualKeyCode::Right | VirtualKeyCode::D => column += 1,
+                VirtualKeyCode::Up | VirtualKeyCode::W => row += 1,
+                VirtualKeyCode::Down | VirtualKeyCode::S => row -= 1,
+                VirtualKeyCode::Space | VirtualKeyCode::Return => click = true,
+                VirtualKeyCode::Q => scroll -= 1,
+                VirtualKeyCode::E => scroll += 1,
+                _ => {}
+            }
+        }
+        let rows = ((self.camh - 48.) / 32.).floor() as i32;
+        self.keyboard_cell = (nalgebra::clamp(column, 0, 6), nalgebra::clamp(row, 0, rows));
+
+        let (column, row) = self.keyboard_cell;
+        Input {
+            cursor_x: 80. + column as f32 * 32.,
+            cursor_y: self.camh - 48. - row as f32 * 32.,
+            cursor_moved: true,
+            lmb_pressed: click,
+            lmb_released: click,
+            scroll,
+            keys_pressed: Vec::new(),
+            keys_held: Vec::new(),
+            focus_lost: input.focus_lost,
+        }
+    }
+
+    fn items(&self, kind: ScreenKind) -> Vec<(Action, String)> {
+        let on_off = |b: bool| if b { "ON" } else { "OFF" };
+        let volume = |v: f32| format!("{:>2}", (v * 10.).round() as u32);
+        let settings = &self.settings;
+        match kind {
+            ScreenKind::Title => {
+                let mut items = vec![
+                    (Action::Play, "PLAY".to_string()),
+                    (Action::Puzzle, "PUZZLE".to_string()),
+                    (Action::Hop, "HOP".to_string()),
+                    (Action::Versus, "VERSUS".to_string()),
+                ];
+                if self.net_config.is_some() {
+                    items.push((Action::Online, "ONLINE".to_string()));
+                }
+                items.push((Action::Options, "OPTIONS".to_string()));
+                items.push((Action::Quit, "QUIT".to_string()));
+                items
+            }
+            ScreenKind::Options => vec![
+                (
+                    Action::MasterVolume,
+                    format!("MASTER VOLUME  {}", volume(settings.volume.master)),
+                ),
+                (
+                    Action::SfxVolume,
+                    format!("SFX VOLUME     {}", volume(settings.volume.sfx)),
+                ),
+                (
+                    Action::MusicVolume,
+                    format!("MUSIC VOLUME   {}", volume(settings.volume.music)),
+                ),
+                (
+                    Action::WindowScale,
+                    format!("WINDOW SCALE   {:>2}", settings.window.scale),
+                ),
+                (
+                    Action::SwapMouseButtons,
+                    format!(
+                        "SWAP MOUSE BUTTONS  {:>3}",
+                        on_off(settings.controls.swap_mouse_buttons)
+                    ),
+                ),
+                (
+                    Action::PauseOnFocusLoss,
+                    format!(
+                        "PAUSE ON FOCUS LOSS {:>3}",
+                        on_off(settings.controls.pause_on_focus_loss)
+                    ),
+                ),
+                (Action::Back, "BACK".to_string()),
+            ],
+            ScreenKind::Gameplay => vec![],
+            ScreenKind::Pause => vec![
+                (Action::Resume, "RESUME".to_string()),
+                (Action::Options, "OPTIONS".to_string()),
+                (Action::QuitToTitle, "QUIT TO TITLE".to_string()),
+            ],
+            ScreenKind::GameOver => vec![
+                (Action::Retry, "RETRY".to_string()),
+                (Action::QuitToTitle, "QUIT TO TITLE".to_string()),
+            ],
+        }
+    }
+
+    fn heading(&self, kind: ScreenKind) -> Vec<String> {
+        match kind {
+            ScreenKind::Title => vec!["BLOCK HOP".to_string()],
+            ScreenKind::Options => vec!["OPTIONS".to_string()],
+            ScreenKind::Gameplay => vec![],
+            ScreenKind::Pause => vec!["PAUSED".to_string()],
+            ScreenKind::GameOver if self.versus => {
+                let (left, right) = match &self.net {
+                    Some(net) => net
+                        .games()
+                        .map_or((None, None), |(l, r)| (Some(l), Some(r))),
+                    None => (self.game.as_ref(), self.rival.as_ref()),
+                };
+                let score = |game: Option<&Game>| game.map(|g| g.score).unwrap_or(0);
+                let over = |game: Option<&Game>| game.map(Game::is_over).unwrap_or(true);
+                let result = match (over(left), over(right)) {
+                    (true, true) => "DRAW",
+                    (false, _) => "LEFT WINS",
+                    (_, false) => "RIGHT WINS",
+                };
+                vec![
+                    result.to_string(),
+                    format!("{:>6} - {:<6}", score(left), score(right)),
+                ]
+            }
+            ScreenKind::GameOver => {
+                let score = self.game.as_ref().map(|g| g.score).unwrap_or(0);
+                let mut lines = vec!["GAME OVER".to_string(), format!("SCORE {}", score)];
+                let scores = self.high_scores.scores().iter().take(Self::SCORES_SHOWN);
+                for (i, s) in scores.enumerate() {
+                    let mark = if self.last_rank == Some(i) { '*' } else { ' ' };
+                    lines.push(format!(
+                        "{}{:>2} {:<12} {:>6}",
+                        mark,
+                        i + 1,
+                        s.name,
+                        s.score
+                    ));
+                }
+                lines
+            }
+        }
+    }
+
+    // top left corner of a menu item
+    fn item_xy(&self, kind: ScreenKind, index: usize, text: &str) -> (f32, f32) {
+        let heading_h = self.heading(kind).len() as f32 * Self::ITEM_SPACING;
+        let count = self.items(kind).len() as f32;
+        let total_h = heading_h + Self::ITEM_SPACING + count * Self::ITEM_SPACING;
+        let y = (self.camh - total_h) / 2. + heading_h + Self::ITEM_SPACING;
+        (
+            ((self.camera().0 - text.len() as f32 * 8.) / 2.).floor(),
+            (y + index as f32 * Self::ITEM_SPACING).floor(),
+        )
+    }
+
+    pub fn tick(&mut self, dt: f32, input: &Input) -> Result<(), GameErr> {
+        let kind = self.top();
+        if kind != ScreenKind::Gameplay {
+            // an online peer can't be paused, keep answering it
+            if let Some(net) = &mut self.net {
+                net.pump();
+            }
+            self.tick_menu(kind, input);
+            return Ok(());
+        }
+
+        let pause = input.key_pressed(VirtualKeyCode::Escape)
+            || input.key_pressed(VirtualKeyCode::P)
+            || (input.focus_lost && self.settings.controls.pause_on_focus_loss);
+        if pause {
+            self.push(ScreenKind::Pause);
+            return Ok(());
+        }
+
+        if self.versus {
+            // the keyboard belongs to the right hand player, unless they're online
+            let keyboard = self.keyboard_input(input);
+            let mouse = match &self.net {
+                Some(_) => input.clone(),
+                None => Input {
+                    keys_pressed: Vec::new(),
+                    keys_held: Vec::new(),
+                    ..input.clone()
+                },
+            };
+            if let Some(net) = &mut self.net {
+                net.tick(dt, &mouse, &keyboard, &self.audio_send)?;
+                if net.is_over() {
+                    self.last_rank = None;
+                    self.push(ScreenKind::GameOver);
+                }
+                return Ok(());
+            }
+
+            let game = self.game.as_mut().unwrap();
+            let rival = self.rival.as_mut().unwrap();
+            versus_tick(game, rival, dt, (&mouse, &keyboard), &self.audio_send)?;
+            if game.is_over() || rival.is_over() {
+                self.last_rank = None;
+                self.push(ScreenKind::GameOver);
+            }
+            return Ok(());
+        }
+
+        let game = self.game.as_mut().unwrap();
+        game.tick(dt, input, &self.audio_send)?;
+        if game.is_over() {
+            let score = Score::now(
+                &self.settings.player.name,
+                game.score,
+                game.seed,
+                game.elapsed,
+                game.blocks_destroyed,
+            );
+            self.last_rank = self.high_scores.insert(score);
+            self.push(ScreenKind::GameOver);
+        }
+        Ok(())
+    }
+
+    fn tick_menu(&mut self, kind: ScreenKind, input: &Input) {
+        let items = self.items(kind);
+        let mut selected = self.stack.last().unwrap().selected;
+        let mut activate = false;
+        let mut adjust = 0;
+
+        // mouse, hovering selects and clicking activates
+        for (i, (_, text)) in items.iter().enumerate() {
+            let (x, y) = self.item_xy(kind, i, text);
+            let w = text.len() as f32 * 8.;
+            let hovered = input.cursor_x >= x - 4.
+                && input.cursor_x < x + w + 4.
+                && input.cursor_y >= y - 1.
+                && input.cursor_y < y + Self::ITEM_SPACING - 1.;
+            if hovered {
+                if input.cursor_moved {
+                    selected = i;
+                }
+                if input.lmb_pressed {
+                    selected = i;
+                    activate = true;
+                    adjust = 1;
+                }
+            }
+        }
+
+        // keyboard
+        for &key in &input.keys_pressed {
+            match key {
+                VirtualKeyCode::Up | VirtualKeyCode::W => {
+                    selected = (selected + items.len() - 1) % items.len()
+                }
+                VirtualKeyCode::Down | VirtualKeyCode::S => selected = (selected + 1) % items.len(),
+                VirtualKeyCode::Left | VirtualKeyCode::A => adjust = -1,
+                VirtualKeyCode::Right | VirtualKeyCode::D => adjust = 1,
+                VirtualKeyCode::Return | VirtualKeyCode::Space => {
+                    activate = true;
+                    adjust = 1;
+                }
+                VirtualKeyCode::Escape => {
+                    self.back(kind);
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.stack.last_mut().unwrap().selected = selected;
+
+        let action = items[selected].0;
+        if adjust != 0 && self.adjust(action, adjust) {
+            return;
+        }
+        if activate {
+            self.activate(action);
+        }
+    }
+
+    // escape, or the back item
+    fn back(&mut self, kind: ScreenKind) {
+        match kind {
+            ScreenKind::Title => self.quit = true,
+            ScreenKind::Options => {
+                self.save_settings();
+                self.pop();
+            }
+            ScreenKind::Pause => self.pop(),
+            ScreenKind::GameOver => self.pop_to(ScreenKind::Title),
+            ScreenKind::Gameplay => {}
+        }
+    }
+
+    fn activate(&mut self, action: Action) {
+        match action {
+            Action::Play => self.start_game(Mode::Classic),
+            Action::Puzzle => self.start_game(Mode::Puzzle),
+            Action::Hop => self.start_game(Mode::Hop),
+            Action::Versus => self.start_versus(),
+            Action::Online => self.start_online(),
+            Action::Options => self.push(ScreenKind::Options),
+            Action::Quit => self.quit = true,
+            Action::Resume => self.pop(),
+            Action::QuitToTitle => self.pop_to(ScreenKind::Title),
+            Action::Retry => {
+                let mode = self.mode;
+                let versus = self.versus;
+                let online = self.net.is_some();
+                // the game over and gameplay screens are replaced without entering the title,
+                // which would switch the music back to the menu track
+                while self.stack.len() > 1 && self.top() != ScreenKind::Title {
+                    self.stack.pop();
+                }
+                self.game = None;
+                self.rival = None;
+                self.net = None;
+                if online {
+                    self.start_online();
+                } else if versus {
+                    self.start_versus();
+                } else {
+                    self.start_game(mode);
+                }
+            }
+            Action::Back => self.back(ScreenKind::Options),
+            _ => {}
+        }
+    }
+
+    // changes an option value, returns false if the action isn't an option
+    fn adjust(&mut self, action: Action, direction: i32) -> bool {
+        let step = |v: &mut f32| *v = nalgebra::clamp(*v + direction as f32 * 0.1, 0., 1.);
+        // made to both the settings in use and the ones saved
+        let change = |settings: &mut Settings| {
+            match action {
+                Action::MasterVolume => step(&mut settings.volume.master),
+                Action::SfxVolume => step(&mut settings.volume.sfx),
+                Action::MusicVolume => step(&mut settings.volume.music),
+                Action::WindowScale => {
+                    settings.window.scale =
+                        nalgebra::clamp(settings.window.scale as i32 + direction, 1, 6) as u32
+                }
+                Action::SwapMouseButtons => {
+                    settings.controls.swap_mouse_buttons = !settings.controls.swap_mouse_buttons
+                }
+                Action::PauseOnFocusLoss => {
+                    settings.controls.pause_on_focus_loss = !settings.controls.pause_on_focus_loss
+                }
+                _ => return false,
+            }
+            true
+        };
+        if !change(&mut self.settings) {
+            return false;
+        }
+        change(&mut self.saved);
+        self.settings_changed = true;
+        let _ = self
+            .audio_send
+            .send(AudioEvent::SetVolume(self.settings.volume));
+        true
+    }
+
+    pub fn render(&mut self, render_state: &mut RenderState) -> Result<(), GameErr> {
+        // draw from the topmost opaque screen upwards
+        let (camw, _) = self.camera();
+        let first = self
+            .stack
+            .iter()
+            .rposition(|screen| !screen.kind.is_overlay())
+            .unwrap_or(0);
+
+        for i in first..self.stack.len() {
+            let kind = self.stack[i].kind;
+            let selected = self.stack[i].selected;
+
+            if kind == ScreenKind::Gameplay {
+                if let Some(net) = &mut self.net {
+                    net.render(render_state, self.camw)?;
+                }
+                if let Some(game) = &mut self.game {
+                    game.render(render_state, (0., 0.))?;
+                }
+                if let Some(rival) = &mut self.rival {
+                    rival.render(render_state, (self.camw, 0.))?;
+                }
+                continue;
+            }
+
+            // background panel
+            let heading = self.heading(kind);
+            let items = self.items(kind);
+            if kind.is_overlay() {
+                let w = items
+                    .iter()
+                    .map(|(_, text)| text.len())
+                    .chain(heading.iter().map(|text| text.len()))
+                    .max()
+                    .unwrap_or(0) as f32
+                    * 8.
+                    + 32.;
+                let h = (heading.len() + items.len() + 1) as f32 * Self::ITEM_SPACING + 16.;
+                render_state.push_panel(
+                    (((camw - w) / 2.).floor(), ((self.camh - h) / 2.).floor()),
+                    (w, h),
+                );
+            } else {
+                render_state.push_panel((0., 0.), (camw, self.camh));
+            }
+
+            // heading, then the items with the selected one marked
+            let (_, items_y) = self.item_xy(kind, 0, "");
+            let top = items_y - (heading.len() + 1) as f32 * Self::ITEM_SPACING;
+            for (j, text) in heading.iter().enumerate() {
+                let x = ((camw - text.len() as f32 * 8.) / 2.).floor();
+                render_state.push_text((x, top + j as f32 * Self::ITEM_SPACING), text);
+            }
+            for (j, (_, text)) in items.iter().enumerate() {
+                let (x, y) = self.item_xy(kind, j, text);
+                render_state.push_text((x, y), text);
+                if j == selected {
+                    render_state.push_text((x - 16., y), ">");
+                    render_state.push_text((x + text.len() as f32 * 8. + 8., y), "<");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
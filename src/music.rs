@@ -0,0 +1,194 @@
+use crate::archive::Assets;
+use rodio::{Decoder, Source};
+use serde_derive::{Deserialize, Serialize};
+use std::{io::Cursor, sync::Arc};
+
+// The music that can be requested by the update thread
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Track {
+    Silence,
+    Menu,
+    Gameplay,
+}
+
+// Which part of a track a layer plays
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Base,
+    Intensity, // only audible as the stack gets higher
+}
+
+// file stems (in audio/music) for each part of a track
+fn track_parts(track: Track) -> &'static [(&'static str, Part)] {
+    match track {
+        Track::Silence => &[],
+        Track::Menu => &[("menu", Part::Base)],
+        Track::Gameplay => &[("game", Part::Base), ("game_intense", Part::Intensity)],
+    }
+}
+
+// reads audio/music/<stem>.ogg or .wav
+fn read_track_file(stem: &str) -> Option<Arc<[u8]>> {
+    let assets = Assets::open().ok()?;
+    ["ogg", "wav"]
+        .iter()
+        .filter_map(|ext| assets.read(&format!("audio/music/{}.{}", stem, ext)).ok())
+        .map(Arc::from)
+        .next()
+}
+
+// A looping file decoded a little at a time, resampled to the output rate
+struct Stream {
+    file: Arc<[u8]>, // still encoded
+    decoder: Option<Decoder<Cursor<Arc<[u8]>>>>,
+    channels: u16,
+    step: f64, // source frames per output sample
+    pos: f64,  // between prev and next
+    prev: f32,
+    next: f32,
+}
+
+impl Stream {
+    fn open(file: Arc<[u8]>, out_rate: u32) -> Option<Stream> {
+        let decoder = Decoder::new(Cursor::new(file.clone())).ok()?;
+        let channels = decoder.channels();
+        let step = decoder.sample_rate() as f64 / out_rate as f64;
+        let mut stream = Stream {
+            file,
+            decoder: Some(decoder),
+            channels,
+            step,
+            pos: 0.,
+            prev: 0.,
+            next: 0.,
+        };
+        stream.next = stream.next_frame();
+        Some(stream)
+    }
+
+    // reads one frame, downmixed to mono, restarting the file when it runs out
+    fn next_frame(&mut self) -> f32 {
+        for _ in 0..2 {
+            if let Some(decoder) = &mut self.decoder {
+                let frame: Vec<i16> = decoder.by_ref().take(self.channels as usize).collect();
+                if frame.len() == self.channels as usize {
+                    return frame.iter().map(|&s| s as f32 / 32768.).sum::<f32>()
+                        / self.channels as f32;
+                }
+            }
+
+            // loop
+            self.decoder = Decoder::new(Cursor::new(self.file.clone())).ok();
+        }
+        0.
+    }
+
+    // adds to out, ramping the gain linearly from gain_start to gain_end
+    fn mix(&mut self, out: &mut [f32], gain_start: f32, gain_end: f32) {
+        let len = out.len() as f32;
+        for (i, s) in out.iter_mut().enumerate() {
+            let gain = gain_start + (gain_end - gain_start) * i as f32 / len;
+            let t = self.pos as f32;
+            *s += (self.prev * (1. - t) + self.next * t) * gain;
+
+            self.pos += self.step;
+            while self.pos >= 1. {
+                self.pos -= 1.;
+                self.prev = self.next;
+                self.next = self.next_frame();
+            }
+        }
+    }
+}
+
+struct Layer {
+    active: bool, // false once it belongs to a previous track
+    part: Part,
+    stream: Stream,
+    gain: f32,
+}
+
+// Plays the requested track, crossfading from the previous one
+pub struct MusicPlayer {
+    rate: u32,
+    track: Track,
+    layers: Vec<Layer>,
+    intensity: f32,
+    pub volume: f32,
+}
+
+impl MusicPlayer {
+    const CROSSFADE_SECS: f32 = 1.5;
+    const INTENSITY_FADE_SECS: f32 = 3.;
+
+    pub fn new(rate: u32) -> MusicPlayer {
+        MusicPlayer {
+            rate,
+            track: Track::Silence,
+            layers: Vec::new(),
+            intensity: 0.,
+            volume: 1.,
+        }
+    }
+
+    // starts streaming a track, the old one fades out as the new one fades in
+    pub fn play(&mut self, track: Track) {
+        if track == self.track {
+            return;
+        }
+        self.track = track;
+        for layer in self.layers.iter_mut() {
+            layer.active = false;
+        }
+
+        for &(stem, part) in track_parts(track) {
+            let stream = match read_track_file(stem).and_then(|f| Stream::open(f, self.rate)) {
+                Some(s) => s,
+                None => {
+                    println!("Missing music: {:?}", stem);
+                    continue;
+                }
+            };
+            self.layers.push(Layer {
+                active: true,
+                part,
+                stream,
+                gain: 0.,
+            });
+        }
+    }
+
+    // 0 is an empty well, 1 is a stack about to overflow
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.intensity = nalgebra::clamp(intensity, 0., 1.);
+    }
+
+    // adds the music to out
+    pub fn mix(&mut self, out: &mut [f32]) {
+        let secs = out.len() as f32 / self.rate as f32;
+        let crossfade = secs / Self::CROSSFADE_SECS;
+        let intensity_fade = secs / Self::INTENSITY_FADE_SECS;
+
+        for layer in self.layers.iter_mut() {
+            // move the gain towards where this layer should be
+            let (target, rate) = match (layer.active, layer.part) {
+                (false, _) => (0., crossfade),
+                (true, Part::Base) => (1., crossfade),
+                (true, Part::Intensity) => (self.intensity, intensity_fade),
+            };
+            let gain = if layer.gain < target {
+                (layer.gain + rate).min(target)
+            } else {
+                (layer.gain - rate).max(target)
+            };
+
+            layer
+                .stream
+                .mix(out, layer.gain * self.volume, gain * self.volume);
+            layer.gain = gain;
+        }
+
+        // drop layers that have faded out
+        self.layers.retain(|layer| layer.active || layer.gain > 0.);
+    }
+}
@@ -0,0 +1,706 @@
+use crate::{
+    audio::AudioEvent,
+    game::{versus_tick, Game, GameErr, Mode},
+    input::Input,
+    level::Level,
+    render::RenderState,
+    settings::Settings,
+    time::get_microseconds_as_u64,
+};
+use bincode::{deserialize, serialize};
+use crossbeam_channel::{Receiver, Sender};
+use glutin::VirtualKeyCode;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+// Online versus, in lockstep
+//  Both peers run both wells from the host's seed and feed them the same inputs on the same
+//  ticks. Local inputs are scheduled DELAY ticks ahead and sent every tick (everything the
+//  peer hasn't acknowledged, so a lost packet is covered by the next one), and a tick is
+//  only simulated once the peer's input for it has arrived. The delay hides the latency of a
+//  decent link, a slower one stalls the round. There's no guessing ahead and rolling back: a
+//  round can't be snapshotted (the physics sets aren't Clone), so a rollback would have to
+//  replay it from the seed.
+//  Every CHECKSUM_INTERVAL ticks each peer hashes both wells and sends its latest hash, a
+//  mismatch means the simulations have drifted apart.
+const VERSION: u8 = 1;
+const DELAY: u32 = 3; // ticks between an input being made and being simulated
+const MAX_INPUTS_PER_PACKET: usize = 64;
+const CHECKSUM_INTERVAL: u32 = 60;
+const MAX_PACKET: usize = 2048;
+
+#[derive(Debug)]
+pub enum NetErr {
+    Bind(String, io::Error),
+    Resolve(String),
+}
+
+impl fmt::Display for NetErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetErr::Bind(addr, e) => write!(f, "could not open a socket on {}: {}", addr, e),
+            NetErr::Resolve(addr) => write!(f, "could not resolve {:?}", addr),
+        }
+    }
+}
+
+impl std::error::Error for NetErr {}
+
+// How to reach the other player, from the command line
+#[derive(Clone, Debug)]
+pub enum NetConfig {
+    Host(u16),    // wait for a peer on this port, playing the left well
+    Join(String), // connect to a host at this address, playing the right well
+    // a peer in this process, played from the keyboard, over a slow and lossy link
+    Simulated { latency_ms: u32, loss: f32 },
+}
+
+// One player's input for one tick, all that's sent over the network
+//  The cursor is sent as a cell, there is no dragging online.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TickInput {
+    pub cell: (i8, i8), // column and row, counted up from the floor
+    pub click: bool,
+    pub scroll: i8,
+    pub tool: u8, // the number key pressed to pick a tool, 0 for none
+}
+
+impl Default for TickInput {
+    fn default() -> TickInput {
+        TickInput {
+            cell: (3, 0),
+            click: false,
+            scroll: 0,
+            tool: 0,
+        }
+    }
+}
+
+impl TickInput {
+    const TOOL_KEYS: [VirtualKeyCode; 6] = [
+        VirtualKeyCode::Key1,
+        VirtualKeyCode::Key2,
+        VirtualKeyCode::Key3,
+        VirtualKeyCode::Key4,
+        VirtualKeyCode::Key5,
+        VirtualKeyCode::Key6,
+    ];
+
+    // the cell under the cursor, a press counts as a click
+    pub fn from_input(input: &Input, camh: f32) -> TickInput {
+        let rows = ((camh - 48.) / 32.).floor();
+        let column = ((input.cursor_x - 80.) / 32.).round();
+        let row = ((camh - 48. - input.cursor_y) / 32.).round();
+        let tool = Self::TOOL_KEYS
+            .iter()
+            .position(|&key| input.key_pressed(key))
+            .map(|i| i as u8 + 1)
+            .unwrap_or(0);
+        TickInput {
+            cell: (
+                nalgebra::clamp(column, 0., 6.) as i8,
+                nalgebra::clamp(row, 0., rows.min(127.)) as i8,
+            ),
+            click: input.lmb_pressed,
+            scroll: nalgebra::clamp(input.scroll, -127, 127) as i8,
+            tool,
+        }
+    }
+
+    // what a well is fed, the cursor at the center of the cell
+    pub fn to_input(self, camh: f32) -> Input {
+        let mut keys_pressed = Vec::new();
+        if self.tool > 0 {
+            keys_pressed.push(Self::TOOL_KEYS[self.tool as usize - 1]);
+        }
+        Input {
+            cursor_x: 80. + self.cell.0 as f32 * 32.,
+            cursor_y: camh - 48. - self.cell.1 as f32 * 32.,
+            cursor_moved: true,
+            lmb_pressed: self.click,
+            lmb_released: self.click,
+            scroll: self.scroll as i32,
+            keys_pressed,
+            keys_held: Vec::new(),
+            focus_lost: false,
+        }
+    }
+
+    // the filler for a tick skipped while catching up: the cursor stays put, nothing pressed
+    fn hold(self) -> TickInput {
+        TickInput {
+            cell: self.cell,
+            ..TickInput::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    version: u8,
+    seed: u64, // of the round, 0 from a peer that hasn't heard it yet
+    ack: u32,  // how many of the receiver's inputs the sender has
+    first_tick: u32,
+    inputs: Vec<TickInput>, // the sender's, from first_tick on
+    checksum: Option<(u32, u64)>,
+}
+
+// Somewhere packets can be sent to and received from the peer, unreliably
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]);
+    // the next packet that has arrived, without blocking
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+// A UDP socket, the host learns the peer's address from the first packet it gets
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+}
+
+impl UdpTransport {
+    pub fn host(port: u16) -> Result<UdpTransport, NetErr> {
+        let addr = format!("0.0.0.0:{}", port);
+        UdpTransport::bind(&addr, None)
+    }
+
+    pub fn join(host: &str) -> Result<UdpTransport, NetErr> {
+        let peer = host
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| NetErr::Resolve(host.to_string()))?;
+        let local = if peer.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        UdpTransport::bind(local, Some(peer))
+    }
+
+    fn bind(addr: &str, peer: Option<SocketAddr>) -> Result<UdpTransport, NetErr> {
+        let socket = UdpSocket::bind(addr)
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            .map_err(|e| NetErr::Bind(addr.to_string(), e))?;
+        Ok(UdpTransport { socket, peer })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) {
+        // a failed send is just another lost packet
+        if let Some(peer) = self.peer {
+            let _ = self.socket.send_to(packet, peer);
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; MAX_PACKET];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).ok()?;
+            let peer = *self.peer.get_or_insert(from);
+            if from == peer {
+                return Some(buf[..len].to_vec());
+            }
+        }
+    }
+}
+
+// One end of an in-process link that delays packets by a fixed latency and drops some
+pub struct SimulatedTransport {
+    send: Sender<(u64, Vec<u8>)>, // with the time it arrives, in microseconds
+    recv: Receiver<(u64, Vec<u8>)>,
+    in_flight: VecDeque<(u64, Vec<u8>)>,
+    latency: u64,
+    loss: f32,
+    rng: StdRng,
+}
+
+// both ends of a simulated link, loss is the chance each packet has of being dropped
+pub fn simulated_link(latency_ms: u32, loss: f32) -> (SimulatedTransport, SimulatedTransport) {
+    let (a_send, b_recv) = crossbeam_channel::unbounded();
+    let (b_send, a_recv) = crossbeam_channel::unbounded();
+    let end = |send, recv| SimulatedTransport {
+        send,
+        recv,
+        in_flight: VecDeque::new(),
+        latency: latency_ms as u64 * 1000,
+        loss,
+        rng: StdRng::from_entropy(),
+    };
+    (end(a_send, a_recv), end(b_send, b_recv))
+}
+
+impl Transport for SimulatedTransport {
+    fn send(&mut self, packet: &[u8]) {
+        if self.rng.gen::<f32>() >= self.loss {
+            let arrival = get_microseconds_as_u64() + self.latency;
+            let _ = self.send.send((arrival, packet.to_vec()));
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.in_flight.extend(self.recv.try_iter());
+        match self.in_flight.front() {
+            Some(&(arrival, _)) if arrival <= get_microseconds_as_u64() => {
+                self.in_flight.pop_front().map(|(_, packet)| packet)
+            }
+            _ => None,
+        }
+    }
+}
+
+// One peer's side of an online round
+pub struct Session {
+    settings: Settings,
+    level: Level,
+    transport: Box<dyn Transport>,
+    local: usize,                // 0 plays the left well, 1 the right
+    seed: Option<u64>,           // the joining peer waits for the host's
+    games: Vec<Game>,            // left and right, empty until the seed is known
+    tick: u32,                   // ticks simulated
+    inputs: [Vec<TickInput>; 2], // each player's inputs by tick
+    acked: usize,                // local inputs the peer is known to have
+    checksums: Vec<(u32, u64)>,  // local, by tick
+    remote_checksums: Vec<(u32, u64)>,
+    desync: Option<u32>, // the first tick the peers were found to disagree on
+    muted: Sender<AudioEvent>, // for the simulated peer, nobody listens
+}
+
+impl Session {
+    pub fn new(
+        settings: &Settings,
+        level: &Level,
+        transport: Box<dyn Transport>,
+        local: usize,
+        seed: Option<u64>,
+    ) -> Session {
+        let (muted, _) = crossbeam_channel::unbounded();
+        let mut session = Session {
+            settings: settings.clone(),
+            level: level.clone(),
+            transport,
+            local,
+            seed: None,
+            games: Vec::new(),
+            tick: 0,
+            inputs: [Vec::new(), Vec::new()],
+            acked: 0,
+            checksums: Vec::new(),
+            remote_checksums: Vec::new(),
+            desync: None,
+            muted,
+        };
+        if let Some(seed) = seed {
+            session.start(seed);
+        }
+        session
+    }
+
+    fn start(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.games = self.new_games(seed);
+        // nobody has input for the first ticks
+        for inputs in self.inputs.iter_mut() {
+            inputs.resize(DELAY as usize, TickInput::default());
+        }
+    }
+
+    fn new_games(&self, seed: u64) -> Vec<Game> {
+        (0..2)
+            .map(|_| Game::new(&self.settings, &self.level, Mode::Classic, seed))
+            .collect()
+    }
+
+    fn remote(&self) -> usize {
+        1 - self.local
+    }
+
+    pub fn games(&self) -> Option<(&Game, &Game)> {
+        match self.games.as_slice() {
+            [left, right] => Some((left, right)),
+            _ => None,
+        }
+    }
+
+    // once a well has overflowed, on a tick both peers simulated with the same inputs
+    pub fn is_over(&self) -> bool {
+        self.games.iter().any(Game::is_over)
+    }
+
+    // keeps the link alive while the round isn't being ticked (paused, or over), so the
+    // peer still gets the inputs it's missing
+    pub fn pump(&mut self) {
+        self.receive();
+        self.send();
+    }
+
+    pub fn tick(
+        &mut self,
+        dt: f32,
+        input: TickInput,
+        audio_send: &Sender<AudioEvent>,
+    ) -> Result<(), GameErr> {
+        self.receive();
+        if self.seed.is_some() {
+            // schedule this tick's input, topping up the delay if catching up ate into it
+            let local = &mut self.inputs[self.local];
+            let due = (self.tick + DELAY) as usize;
+            while local.len() < due {
+                local.push(input.hold());
+            }
+            if local.len() == due {
+                local.push(input);
+            }
+
+            // one tick, or two to catch up with a peer that's ahead
+            if self.step(dt, audio_send)? {
+                let remote = self.inputs[self.remote()].len() as u32;
+                if remote > self.tick + DELAY {
+                    self.step(dt, audio_send)?;
+                }
+            }
+        }
+        self.send();
+        Ok(())
+    }
+
+    // reads every packet that has arrived and compares checksums
+    fn receive(&mut self) {
+        let remote = self.remote();
+        while let Some(bytes) = self.transport.recv() {
+            let packet: Packet = match deserialize(&bytes) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            if packet.version != VERSION || packet.seed == 0 {
+                continue;
+            }
+            if self.seed.is_none() && self.local == 1 {
+                self.start(packet.seed);
+            }
+            if Some(packet.seed) != self.seed {
+                continue;
+            }
+
+            self.acked = self.acked.max(packet.ack as usize);
+            let inputs = &mut self.inputs[remote];
+            let first = packet.first_tick as usize;
+            if first <= inputs.len() {
+                let new = packet.inputs.iter().skip(inputs.len() - first);
+                inputs.extend(new);
+            }
+            if let Some(checksum) = packet.checksum {
+                self.remote_checksums.push(checksum);
+            }
+        }
+
+        // compare the checksums both sides have, the peer's can be ahead of ours
+        let local = &self.checksums;
+        for &(tick, checksum) in &self.remote_checksums {
+            let mine = local.iter().find(|&&(t, _)| t == tick);
+            if mine.map(|&(_, c)| c != checksum).unwrap_or(false) && self.desync.is_none() {
+                println!("Desync at tick {}", tick);
+                self.desync = Some(tick);
+            }
+        }
+        self.remote_checksums
+            .retain(|&(tick, _)| !local.iter().any(|&(t, _)| t == tick));
+    }
+
+    // everything the peer hasn't acknowledged, and the latest checksum
+    fn send(&mut self) {
+        let seed = self.seed.unwrap_or(0);
+        let local = &self.inputs[self.local];
+        let first = self.acked.min(local.len());
+        let last = local.len().min(first + MAX_INPUTS_PER_PACKET);
+        let known = self.inputs[self.remote()].len() as u32;
+        let packet = Packet {
+            version: VERSION,
+            seed,
+            ack: known,
+            first_tick: first as u32,
+            inputs: local[first..last].to_vec(),
+            checksum: self.checksums.last().cloned(),
+        };
+        if let Ok(bytes) = serialize(&packet) {
+            self.transport.send(&bytes);
+        }
+    }
+
+    // simulates the next tick, returns false if it has to wait for the peer
+    fn step(&mut self, dt: f32, audio_send: &Sender<AudioEvent>) -> Result<bool, GameErr> {
+        let tick = self.tick as usize;
+        let local = match self.inputs[self.local].get(tick) {
+            Some(&input) => input,
+            None => return Ok(false),
+        };
+        let remote = match self.inputs[self.remote()].get(tick) {
+            Some(&input) => input,
+            None => return Ok(false),
+        };
+
+        let camh = self.settings.camera.height;
+        let mut inputs = [local.to_input(camh), remote.to_input(camh)];
+        if self.local == 1 {
+            inputs.swap(0, 1);
+        }
+        if let [left, right] = self.games.as_mut_slice() {
+            versus_tick(left, right, dt, (&inputs[0], &inputs[1]), audio_send)?;
+        }
+        self.tick += 1;
+
+        if self.tick % CHECKSUM_INTERVAL == 0 {
+            let checksum = self
+                .games
+                .iter()
+                .fold(0, |acc: u64, game| acc.rotate_left(1) ^ game.checksum());
+            self.checksums.push((self.tick, checksum));
+        }
+        Ok(true)
+    }
+
+    pub fn render(&mut self, render_state: &mut RenderState, camw: f32) -> Result<(), GameErr> {
+        for (i, game) in self.games.iter_mut().enumerate() {
+            game.render(render_state, (i as f32 * camw, 0.))?;
+        }
+
+        let status = if let Some(tick) = self.desync {
+            Some(format!("DESYNC AT TICK {}", tick))
+        } else if self.inputs[self.remote()].len() <= DELAY as usize {
+            Some("WAITING FOR PEER".to_string())
+        } else {
+            None
+        };
+        if let Some(text) = status {
+            let x = (camw - text.len() as f32 * 8. / 2.).floor();
+            render_state.push_text((x, 18.), &text);
+        }
+        Ok(())
+    }
+}
+
+// An online round: this player's session, and in a simulated match the peer's as well
+pub struct NetMatch {
+    session: Session,
+    peer: Option<Session>, // played from the keyboard, heard and seen by nobody
+}
+
+impl NetMatch {
+    pub fn start(
+        config: &NetConfig,
+        settings: &Settings,
+        level: &Level,
+    ) -> Result<NetMatch, NetErr> {
+        // never 0, that means "no seed yet" on the wire
+        let seed = rand::thread_rng().gen_range(1, u64::max_value());
+        let net_match = match config {
+            NetConfig::Host(port) => {
+                let transport = Box::new(UdpTransport::host(*port)?);
+                println!("Hosting on port {}", port);
+                NetMatch {
+                    session: Session::new(settings, level, transport, 0, Some(seed)),
+                    peer: None,
+                }
+            }
+            NetConfig::Join(host) => {
+                let transport = Box::new(UdpTransport::join(host)?);
+                NetMatch {
+                    session: Session::new(settings, level, transport, 1, None),
+                    peer: None,
+                }
+            }
+            NetConfig::Simulated { latency_ms, loss } => {
+                let (a, b) = simulated_link(*latency_ms, *loss);
+                NetMatch {
+                    session: Session::new(settings, level, Box::new(a), 0, Some(seed)),
+                    peer: Some(Session::new(settings, level, Box::new(b), 1, None)),
+                }
+            }
+        };
+        Ok(net_match)
+    }
+
+    pub fn games(&self) -> Option<(&Game, &Game)> {
+        self.session.games()
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.session.is_over()
+    }
+
+    pub fn pump(&mut self) {
+        self.session.pump();
+        if let Some(peer) = &mut self.peer {
+            peer.pump();
+        }
+    }
+
+    // mouse for this player, keyboard for the simulated peer
+    pub fn tick(
+        &mut self,
+        dt: f32,
+        mouse: &Input,
+        keyboard: &Input,
+        audio_send: &Sender<AudioEvent>,
+    ) -> Result<(), GameErr> {
+        let camh = self.session.settings.camera.height;
+        self.session
+            .tick(dt, TickInput::from_input(mouse, camh), audio_send)?;
+        if let Some(peer) = &mut self.peer {
+            let muted = peer.muted.clone();
+            peer.tick(dt, TickInput::from_input(keyboard, camh), &muted)?;
+        }
+        Ok(())
+    }
+
+    pub fn render(&mut self, render_state: &mut RenderState, camw: f32) -> Result<(), GameErr> {
+        self.session.render(render_state, camw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const DT: f32 = 1. / 60.;
+    const END: u32 = 2 * CHECKSUM_INTERVAL;
+    const SEED: u64 = 12_345;
+
+    fn level() -> Level {
+        toml::from_str(include_str!("../levels/default.toml")).unwrap()
+    }
+
+    // a cursor that keeps moving and clicking
+    fn script(player: usize, tick: usize) -> TickInput {
+        TickInput {
+            cell: (((tick / 4 + player * 3) % 7) as i8, (tick / 20 % 3) as i8),
+            click: tick % 15 == player * 7,
+            scroll: 0,
+            tool: 0,
+        }
+    }
+
+    // the host's session, with nobody on the other end of the link
+    fn alone() -> Session {
+        let (transport, _) = simulated_link(0, 0.);
+        let settings = Settings::default();
+        Session::new(&settings, &level(), Box::new(transport), 0, Some(SEED))
+    }
+
+    // a round with both players' inputs known up front, run for END ticks
+    fn played() -> Session {
+        let mut session = alone();
+        for (player, inputs) in session.inputs.iter_mut().enumerate() {
+            inputs.extend((DELAY as usize..END as usize).map(|tick| script(player, tick)));
+        }
+        let (muted, _) = crossbeam_channel::unbounded();
+        while session.tick < END {
+            assert!(session.step(DT, &muted).unwrap());
+        }
+        session
+    }
+
+    #[test]
+    fn peers_agree_over_a_lossy_link() {
+        let settings = Settings::default();
+        let level = level();
+        let (a, b) = simulated_link(30, 0.2);
+        let mut sessions = [
+            Session::new(&settings, &level, Box::new(a), 0, Some(SEED)),
+            Session::new(&settings, &level, Box::new(b), 1, None),
+        ];
+        let (muted, _) = crossbeam_channel::unbounded();
+
+        // both peers run END ticks (or one over, catching up)
+        let start = Instant::now();
+        while sessions.iter().any(|session| session.tick < END) {
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "the peers never got there"
+            );
+            for (player, session) in sessions.iter_mut().enumerate() {
+                if session.tick < END {
+                    let input = script(player, session.inputs[player].len());
+                    session.tick(DT, input, &muted).unwrap();
+                } else {
+                    session.pump();
+                }
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        // line the peers up on the same tick
+        let last = sessions.iter().map(|session| session.tick).max().unwrap();
+        while sessions.iter().any(|session| session.tick < last) {
+            assert!(start.elapsed() < Duration::from_secs(60));
+            for session in sessions.iter_mut() {
+                session.pump();
+                if session.tick < last {
+                    session.step(DT, &muted).unwrap();
+                }
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        let [a, b] = &sessions;
+        let (a_left, a_right) = a.games().unwrap();
+        let (b_left, b_right) = b.games().unwrap();
+        assert_eq!(a_left.checksum(), b_left.checksum());
+        assert_eq!(a_right.checksum(), b_right.checksum());
+        assert_eq!(&a.checksums[..2], &b.checksums[..2]);
+        assert_eq!(a.desync, None);
+        assert_eq!(b.desync, None);
+    }
+
+    // the host, fed the peer's inputs for the whole round in one packet along with the
+    // peer's checksum of a tick the host hasn't simulated yet
+    fn fed_in_one_packet(checksum: (u32, u64)) -> Session {
+        let reference = played();
+        let (transport, mut peer) = simulated_link(0, 0.);
+        let settings = Settings::default();
+        let mut session = Session::new(&settings, &level(), Box::new(transport), 0, Some(SEED));
+        session.inputs[0] = reference.inputs[0].clone();
+        let packet = Packet {
+            version: VERSION,
+            seed: SEED,
+            ack: END,
+            first_tick: 0,
+            inputs: reference.inputs[1].clone(),
+            checksum: Some(checksum),
+        };
+        peer.send(&serialize(&packet).unwrap());
+
+        let (muted, _) = crossbeam_channel::unbounded();
+        while session.tick < END {
+            session.tick(DT, TickInput::default(), &muted).unwrap();
+        }
+        session.pump();
+        assert!(session.remote_checksums.is_empty());
+        session
+    }
+
+    #[test]
+    fn checksum_with_the_inputs_is_checked_once_simulated() {
+        let reference = played();
+        let session = fed_in_one_packet(reference.checksums[0]);
+        assert_eq!(&session.checksums[..2], &reference.checksums[..2]);
+        assert_eq!(session.desync, None);
+    }
+
+    #[test]
+    fn wrong_checksum_is_a_desync() {
+        let (tick, checksum) = played().checksums[0];
+        let session = fed_in_one_packet((tick, !checksum));
+        assert_eq!(session.desync, Some(CHECKSUM_INTERVAL));
+    }
+}
@@ -1,77 +1,250 @@
-use crate::{camera::camera, io::get_root};
-use crossbeam_channel::Receiver;
+use crate::{
+    archive::{ArchiveErr, Assets},
+    camera::camera,
+    shutdown::Shutdown,
+    time::get_microseconds_as_u64,
+};
 use ezgl::*;
-use glutin::GlWindow;
-use std::{collections::HashMap, fs::read_dir, iter::FromIterator};
-
+use glutin::{dpi::LogicalSize, GlWindow};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RenderState {
+    pub time: u64,            // the simulated time this state is from, in microseconds
+    pub sprite_ids: Vec<u32>, // 0 for sprites that aren't interpolated
     pub sprite_xys: Vec<(f32, f32)>,
     pub sprite_uvs: Vec<(f32, f32)>,
     pub sprite_whs: Vec<(f32, f32)>,
     pub sprite_rghs: Vec<(f32, f32, f32)>,
     pub debug: bool,
-    pub wireboxes: Option<Vec<(f32, f32, f32, f32)>>,
+    pub wireframes: Option<Vec<Vec<(f32, f32)>>>, // convex outlines of the colliders
     pub rigid_bodies: Option<Vec<(f32, f32)>>,
+    pub window_scale: f64,
+    pub camera: (f32, f32), // the size of the visible area, the window is this times the scale
+}
+
+impl RenderState {
+    pub fn new(window_scale: f64, camera: (f32, f32), time: u64) -> RenderState {
+        RenderState {
+            time,
+            sprite_ids: Vec::new(),
+            sprite_xys: Vec::new(),
+            sprite_uvs: Vec::new(),
+            sprite_whs: Vec::new(),
+            sprite_rghs: Vec::new(),
+            debug: false,
+            wireframes: None,
+            rigid_bodies: None,
+            window_scale,
+            camera,
+        }
+    }
+
+    pub fn push_sprite(&mut self, xy: (f32, f32), uv: (f32, f32), wh: (f32, f32)) {
+        self.sprite_ids.push(0);
+        self.sprite_xys.push(xy);
+        self.sprite_uvs.push(uv);
+        self.sprite_whs.push(wh);
+        self.sprite_rghs.push((0., 0., 0.));
+    }
+
+    // draws text with the 8x14 font at the bottom of mastercomp.png, xy being the top left corner
+    pub fn push_text(&mut self, xy: (f32, f32), text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            let c = c as u32;
+            if c < 33 || c > 126 {
+                continue;
+            }
+            let uv = ((c - 33) as f32 * 8., 240.);
+            self.push_sprite((xy.0 + i as f32 * 8., xy.1), uv, (8., 14.));
+        }
+    }
+
+    // fills a region with the solid dark tile in mastercomp.png
+    pub fn push_panel(&mut self, xy: (f32, f32), wh: (f32, f32)) {
+        let tile = 16.;
+        let mut y = 0.;
+        while y < wh.1 {
+            let mut x = 0.;
+            while x < wh.0 {
+                let tile_wh = (f32::min(tile, wh.0 - x), f32::min(tile, wh.1 - y));
+                self.push_sprite((xy.0 + x, xy.1 + y), (392., 88.), tile_wh);
+                x += tile;
+            }
+            y += tile;
+        }
+    }
+}
+
+// The newest two render states, published by the update thread after every tick
+#[derive(Clone, Default)]
+pub struct Snapshots(Arc<Mutex<(Option<Arc<RenderState>>, Option<Arc<RenderState>>)>>);
+
+impl Snapshots {
+    pub fn publish(&self, render_state: RenderState) {
+        let mut states = self.0.lock().unwrap();
+        states.0 = states.1.take();
+        states.1 = Some(Arc::new(render_state));
+    }
+
+    // (previous, latest), previous being latest until a second state is published
+    fn get(&self) -> Option<(Arc<RenderState>, Arc<RenderState>)> {
+        let states = self.0.lock().unwrap();
+        let latest = states.1.clone()?;
+        let previous = states.0.clone().unwrap_or_else(|| latest.clone());
+        Some((previous, latest))
+    }
+}
+
+// Blends two render states for the time now, matching sprites by id
+//  Rendering a tick behind the simulation means there's always a state on either side,
+//  alpha is 0 when latest was just published and 1 a tick later.
+fn interpolate(previous: &RenderState, latest: &RenderState, now: u64) -> RenderState {
+    let mut state = latest.clone();
+    let span = latest.time.saturating_sub(previous.time);
+    if span == 0 {
+        return state;
+    }
+    let alpha = nalgebra::clamp(now.saturating_sub(latest.time) as f32 / span as f32, 0., 1.);
+
+    let previous_ids: HashMap<u32, usize> = previous
+        .sprite_ids
+        .iter()
+        .enumerate()
+        .filter(|&(_, &id)| id != 0)
+        .map(|(i, &id)| (id, i))
+        .collect();
+    for (i, id) in latest.sprite_ids.iter().enumerate() {
+        let j = match previous_ids.get(id) {
+            Some(&j) => j,
+            None => continue,
+        };
+
+        let (a, b) = (previous.sprite_xys[j], latest.sprite_xys[i]);
+        state.sprite_xys[i] = (a.0 + (b.0 - a.0) * alpha, a.1 + (b.1 - a.1) * alpha);
+
+        // rotate the short way around
+        let (a, b) = (previous.sprite_rghs[j].0, latest.sprite_rghs[i].0);
+        let mut dr = (b - a) % (2. * PI);
+        if dr > PI {
+            dr -= 2. * PI;
+        } else if dr < -PI {
+            dr += 2. * PI;
+        }
+        state.sprite_rghs[i].0 = a + dr * alpha;
+    }
+    state
 }
 
 #[derive(Debug)]
 pub enum RenderErr {
-    Location(u32, u32),
+    Context(glutin::ContextError),
+    Asset(ArchiveErr),
+    Texture(String),        // asset name
+    Shader(String, String), // asset name, compile log
+    Program(String),        // link log
 }
 
-pub fn render(
-    camw: f32,
-    camh: f32,
-    window: GlWindow,
-    render_recv: Receiver<RenderState>,
-) -> Result<(), RenderErr> {
+impl fmt::Display for RenderErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderErr::Context(e) => write!(f, "gl context error: {}", e),
+            RenderErr::Asset(e) => write!(f, "{}", e),
+            RenderErr::Texture(name) => write!(f, "could not load texture {:?}", name),
+            RenderErr::Shader(name, log) => write!(f, "could not compile {:?}:\n{}", name, log),
+            RenderErr::Program(log) => write!(f, "could not link shader program:\n{}", log),
+        }
+    }
+}
+
+impl std::error::Error for RenderErr {}
+
+pub fn render(window: GlWindow, snapshots: Snapshots, shutdown: Shutdown) -> Result<(), RenderErr> {
     // build gl context
     unsafe {
         use crate::glutin::GlContext;
 
-        window
-            .make_current()
-            .map_err(|_| RenderErr::Location(column!(), line!()))?;
+        window.make_current().map_err(RenderErr::Context)?;
         gl::load_with(|symbol| window.get_proc_address(symbol) as *const _);
         gl::ClearColor(0., 1., 0., 1.);
-    
+
         InstantDraw::bind_vao();
     }
 
-    // load textures
-    let textures: HashMap<String, Texture2D> =
-        HashMap::from_iter(read_dir(get_root().join("textures")).unwrap().filter_map(
-            |direntry_res| {
-                let path = direntry_res.ok()?.path();
-                let name = path.file_name()?.to_string_lossy().into_owned();
-                let texture = Texture2D::from_file(&path).ok()?;
-                println!(
-                    "Loaded texture: {:?} as {:?} (0x{:x})",
-                    path, name, texture.format
-                );
-                Some((name, texture))
-            },
-        ));
+    // load textures, from assets.pak or textures/
+    let assets = Assets::open().map_err(RenderErr::Asset)?;
+    let mut textures: HashMap<String, Texture2D> = HashMap::new();
+    for name in assets.list("textures") {
+        let data = assets.read(&name).map_err(RenderErr::Asset)?;
+        let texture =
+            Texture2D::from_memory(&data).map_err(|_| RenderErr::Texture(name.clone()))?;
+        println!("Loaded texture: {:?} (0x{:x})", name, texture.format);
+        textures.insert(name, texture);
+    }
+    let mastercomp = textures
+        .get("textures/mastercomp.png")
+        .ok_or_else(|| RenderErr::Asset(ArchiveErr::Missing("textures/mastercomp.png".into())))?;
 
     // todo: load all programs
+    let load_shader = |name: &str| -> Result<Shader, RenderErr> {
+        let source = assets.read(name).map_err(RenderErr::Asset)?;
+        let kind = if name.ends_with(".vert") {
+            gl::VERTEX_SHADER
+        } else {
+            gl::FRAGMENT_SHADER
+        };
+        Shader::from_source(kind, &String::from_utf8_lossy(&source))
+            .map_err(|e| RenderErr::Shader(name.to_string(), format!("{:?}", e)))
+    };
     let sprite_program = ProgramBuilder::new()
-        .with(Shader::from_file(&get_root().join("shaders/sprite.vert")).unwrap())
-        .with(Shader::from_file(&get_root().join("shaders/sprite.frag")).unwrap())
+        .with(load_shader("shaders/sprite.vert")?)
+        .with(load_shader("shaders/sprite.frag")?)
         .build()
-        .unwrap();
+        .map_err(|e| RenderErr::Program(format!("{:?}", e)))?;
     let wireframe_program = ProgramBuilder::new()
-        .with(Shader::from_file(&get_root().join("shaders/wireframe.vert")).unwrap())
-        .with(Shader::from_file(&get_root().join("shaders/wireframe.frag")).unwrap())
+        .with(load_shader("shaders/wireframe.vert")?)
+        .with(load_shader("shaders/wireframe.frag")?)
         .build()
-        .unwrap();
-
-    loop {
-        // get a render state from the update thread
-        let render_state = match render_recv.recv() {
-            Ok(s) => s,
-            Err(_) => return Ok(()),
+        .map_err(|e| RenderErr::Program(format!("{:?}", e)))?;
+
+    // render loop
+    //  Runs as fast as vsync allows, independent of the update thread's tick rate. Each
+    //  frame draws the newest two render states blended for the current time.
+    let mut window_scale = 0.;
+    let mut camera_wh = (0., 0.);
+    while !shutdown.is_requested() {
+        let render_state = match snapshots.get() {
+            Some((previous, latest)) => interpolate(&previous, &latest, get_microseconds_as_u64()),
+            None => {
+                // nothing published yet
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
         };
 
+        // resize the window if the scale setting or the camera changed
+        let (camw, camh) = render_state.camera;
+        if render_state.window_scale != window_scale || render_state.camera != camera_wh {
+            use crate::glutin::GlContext;
+
+            window_scale = render_state.window_scale;
+            camera_wh = render_state.camera;
+            let size = LogicalSize::new(camw as f64 * window_scale, camh as f64 * window_scale);
+            window.set_inner_size(size);
+            let size = size.to_physical(window.get_hidpi_factor());
+            window.resize(size);
+            unsafe {
+                gl::Viewport(0, 0, size.width as i32, size.height as i32);
+            }
+        }
+
         // clear
         unsafe {
             gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
@@ -175,74 +348,51 @@ pub fn render(
                 .with_buffer(&model_location_data, 2)
                 .with_buffer(&model_origin_data, 3)
                 .with_buffer(&model_rotation_data, 4)
-                .with_texture(&textures["mastercomp.png"], 0)
+                .with_texture(mastercomp, 0)
                 .with_uniform(GLSLAny::Mat3(pos_transform), 1)
                 .with_uniform(GLSLAny::Vec2(tex_transform), 2)
                 .enable_blend(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA)
                 .draw();
         }
 
-        // render wireboxes
-        if let Some(wireboxes) = render_state.wireboxes {
-            let count = wireboxes.len();
-
-            // pos
-            let pos = wireboxes
-                .iter()
-                .fold(Vec::with_capacity(count * 4), |mut v, wirebox| {
-                    v.push((wirebox.0, wirebox.1));
-                    v.push((wirebox.0 + wirebox.2, wirebox.1));
-                    v.push((wirebox.0 + wirebox.2, wirebox.1 + wirebox.3));
-                    v.push((wirebox.0, wirebox.1 + wirebox.3));
-                    v
-                });
+        // render wireframes
+        //  Each convex outline is drawn as a fan of triangles from its center. Only the outer
+        //  edge of each triangle has a barycentric coordinate near 0, so only it gets a line.
+        if let Some(wireframes) = render_state.wireframes {
+            // pos and bc
+            let mut pos = Vec::new();
+            let mut bc = Vec::new();
+            for outline in wireframes.iter().filter(|outline| outline.len() >= 3) {
+                let n = outline.len() as f32;
+                let center = outline
+                    .iter()
+                    .fold((0., 0.), |c, p| (c.0 + p.0 / n, c.1 + p.1 / n));
+                for (i, &point) in outline.iter().enumerate() {
+                    pos.push(center);
+                    pos.push(point);
+                    pos.push(outline[(i + 1) % outline.len()]);
+                    bc.push((1., 1.));
+                    bc.push((0., 1.));
+                    bc.push((0., 1.));
+                }
+            }
+            let count = pos.len() / 3;
             let vert_data = Buffer::<(f32, f32)>::from(gl::ARRAY_BUFFER, &pos[..]);
+            let bc_data = Buffer::<(f32, f32)>::from(gl::ARRAY_BUFFER, &bc[..]);
 
             // color
-            let color = wireboxes
-                .iter()
-                .fold(Vec::with_capacity(count * 4), |mut v, _| {
-                    v.push((1., 0., 0., 1.));
-                    v.push((1., 0., 0., 1.));
-                    v.push((1., 0., 0., 1.));
-                    v.push((1., 0., 0., 1.));
-                    v
-                });
+            let color = vec![(1., 0., 0., 1.); pos.len()];
             let color_data = Buffer::<(f32, f32, f32, f32)>::from(gl::ARRAY_BUFFER, &color[..]);
 
-            // bc
-            let bc = wireboxes
-                .iter()
-                .fold(Vec::with_capacity(count * 4), |mut v, _| {
-                    v.push((0., 1.));
-                    v.push((0., 0.));
-                    v.push((1., 0.));
-                    v.push((0., 0.));
-                    /*v.push(((temp >> 0 & 1) as f32, (temp >> 1 & 1) as f32));
-                    v.push(((temp >> 2 & 1) as f32, (temp >> 3 & 1) as f32));
-                    v.push(((temp >> 4 & 1) as f32, (temp >> 6 & 1) as f32));
-                    v.push(((temp >> 6 & 1) as f32, (temp >> 7 & 1) as f32));*/
-                    v
-                });
-            let bc_data = Buffer::<(f32, f32)>::from(gl::ARRAY_BUFFER, &bc[..]);
-
             // ibo
-            let ele = (0..count as u32).fold(Vec::with_capacity(count * 6), |mut v, num| {
-                v.push(num * 4);
-                v.push(num * 4 + 1);
-                v.push(num * 4 + 2);
-                v.push(num * 4 + 2);
-                v.push(num * 4 + 3);
-                v.push(num * 4);
-                v
-            });
+            let ele: Vec<u32> = (0..pos.len() as u32).collect();
             let ibo = Buffer::<u32>::from(gl::ELEMENT_ARRAY_BUFFER, &ele);
 
             // position transform
             let pos_transform = camera(0., 0., camw, camh);
 
             // draw
-            InstantDraw::start_tri_draw(count as u32 * 2, &wireframe_program, &ibo)
+            InstantDraw::start_tri_draw(count as u32, &wireframe_program, &ibo)
                 .with_buffer(&vert_data, 0)
                 .with_buffer(&color_data, 1)
                 .with_buffer(&bc_data, 2)
@@ -321,8 +471,12 @@ pub fn render(
         }
 
         // swap buffer
-        window
-            .swap_buffers()
-            .map_err(|_| RenderErr::Location(column!(), line!()))?;
+        window.swap_buffers().map_err(RenderErr::Context)?;
     }
+
+    // the game is closing, free gl resources while the context is still current
+    drop(sprite_program);
+    drop(wireframe_program);
+    drop(textures);
+    Ok(())
 }
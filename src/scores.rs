@@ -0,0 +1,232 @@
+use crate::io::{get_data_dir, read_file, save_to_file, IoErr};
+use bincode::deserialize;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const VERSION: u32 = 1; // bump when Score changes, older tables are then discarded
+pub const MAX_SCORES: usize = 10;
+
+// A finished round
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Score {
+    pub name: String,
+    pub score: u32,
+    pub date: u64, // seconds since the unix epoch
+    pub seed: u64,
+    pub duration: f32, // seconds
+    pub blocks_destroyed: u32,
+}
+
+impl Score {
+    pub fn now(name: &str, score: u32, seed: u64, duration: f32, blocks_destroyed: u32) -> Score {
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Score {
+            name: name.to_string(),
+            score,
+            date,
+            seed,
+            duration,
+            blocks_destroyed,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScoreFile {
+    version: u32, // first, so it can be read without knowing the rest of the layout
+    scores: Vec<Score>,
+}
+
+// The top MAX_SCORES scores, best first, saved in the user data directory
+pub struct HighScores {
+    path: PathBuf,
+    scores: Vec<Score>,
+    unsaved: bool,
+}
+
+impl HighScores {
+    // file is the table's name in the data directory
+    //  A missing file is an empty table, as is one from another version or a corrupt one
+    //  (which is kept aside as .bak).
+    pub fn load(file: &str) -> HighScores {
+        HighScores::load_from(get_data_dir().join(file))
+    }
+
+    fn load_from(path: PathBuf) -> HighScores {
+        let scores = match read_file(&path) {
+            Ok(data) => decode(&path, &data),
+            Err(IoErr::Open(_, ref e)) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                println!("Could not load high scores: {}", e);
+                Vec::new()
+            }
+        };
+
+        let mut high_scores = HighScores {
+            path,
+            scores,
+            unsaved: false,
+        };
+        high_scores.tidy();
+        high_scores
+    }
+
+    pub fn scores(&self) -> &[Score] {
+        &self.scores
+    }
+
+    // adds a score and saves, returns its rank (0 being the best) if it made the table
+    pub fn insert(&mut self, score: Score) -> Option<usize> {
+        let rank = self
+            .scores
+            .iter()
+            .position(|s| score.score > s.score)
+            .unwrap_or(self.scores.len());
+        if rank >= MAX_SCORES {
+            return None;
+        }
+        self.scores.insert(rank, score);
+        self.tidy();
+        self.unsaved = true;
+        self.save();
+        Some(rank)
+    }
+
+    // writes the table if it has changed since the last successful save
+    pub fn save(&mut self) {
+        if !self.unsaved {
+            return;
+        }
+        let file = ScoreFile {
+            version: VERSION,
+            scores: self.scores.clone(),
+        };
+        match save_to_file(file, &self.path) {
+            Ok(()) => self.unsaved = false,
+            Err(e) => println!("Could not save high scores: {}", e),
+        }
+    }
+
+    // keeps the table sorted and in bounds, whatever was read from disk
+    fn tidy(&mut self) {
+        self.scores.sort_by(|a, b| b.score.cmp(&a.score));
+        self.scores.truncate(MAX_SCORES);
+    }
+}
+
+// the scores in a table file, the version is checked before the body is decoded since an
+// older layout wouldn't decode as this one
+fn decode(path: &Path, data: &[u8]) -> Vec<Score> {
+    match deserialize::<u32>(data) {
+        Ok(version) if version != VERSION => {
+            println!("Discarding high scores from an older version");
+            return Vec::new();
+        }
+        Ok(_) => {}
+        Err(_) => {
+            backup(path);
+            return Vec::new();
+        }
+    }
+    match deserialize::<ScoreFile>(data) {
+        Ok(file) => file.scores,
+        Err(_) => {
+            backup(path);
+            Vec::new()
+        }
+    }
+}
+
+fn backup(path: &Path) {
+    let bak = path.with_extension("bin.bak");
+    println!(
+        "High scores at {:?} are corrupt, moving them to {:?} and starting over",
+        path, bak
+    );
+    let _ = std::fs::rename(path, bak);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let file = format!("blockhop_scores_{}_{}.bin", name, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("bin.bak"));
+        path
+    }
+
+    // loads the table at path, returns it and whether the file was moved aside as corrupt
+    fn load(path: &Path) -> (Vec<Score>, bool) {
+        let scores = HighScores::load_from(path.to_path_buf()).scores;
+        let bak = path.with_extension("bin.bak");
+        let backed_up = bak.exists();
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(bak);
+        (scores, backed_up)
+    }
+
+    fn saved_table(path: &Path) {
+        let mut high_scores = HighScores::load_from(path.to_path_buf());
+        for score in &[300, 100, 200] {
+            high_scores.insert(Score::now("test", *score, 1, 60., 10));
+        }
+    }
+
+    #[test]
+    fn saved_table_loads_in_order() {
+        let path = temp_path("saved");
+        saved_table(&path);
+        let (scores, backed_up) = load(&path);
+        let points: Vec<u32> = scores.iter().map(|s| s.score).collect();
+        assert_eq!(points, vec![300, 200, 100]);
+        assert!(!backed_up);
+    }
+
+    #[test]
+    fn truncated_table_is_moved_aside() {
+        let path = temp_path("truncated");
+        saved_table(&path);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+        let (scores, backed_up) = load(&path);
+        assert!(scores.is_empty());
+        assert!(backed_up);
+    }
+
+    #[test]
+    fn garbage_is_moved_aside() {
+        let path = temp_path("garbage");
+        let mut garbage = VERSION.to_le_bytes().to_vec();
+        garbage.extend_from_slice(&[0xff; 7]);
+        std::fs::write(&path, &garbage).unwrap();
+        let (scores, backed_up) = load(&path);
+        assert!(scores.is_empty());
+        assert!(backed_up);
+
+        std::fs::write(&path, &[1, 2]).unwrap();
+        let (scores, backed_up) = load(&path);
+        assert!(scores.is_empty());
+        assert!(backed_up);
+    }
+
+    #[test]
+    fn older_version_is_discarded_without_a_backup() {
+        // a layout this version can't decode
+        let path = temp_path("older");
+        let mut old = (VERSION - 1).to_le_bytes().to_vec();
+        old.extend_from_slice(b"an older layout");
+        std::fs::write(&path, &old).unwrap();
+        let (scores, backed_up) = load(&path);
+        assert!(scores.is_empty());
+        assert!(!backed_up);
+    }
+}
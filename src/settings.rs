@@ -0,0 +1,646 @@
+use crate::{components::BlockKind, io::get_root, net::NetConfig, stream::StreamSource};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+const SETTINGS_FILE: &str = "settings.toml";
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Camera {
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Window {
+    pub scale: u32,
+    pub vsync: bool,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Physics {
+    pub gravity: f32,
+    pub tick_rate: u32, // update ticks per second, independent of the frame rate
+    pub velocity_iterations: u32, // solver passes over the contacts each step
+    pub position_iterations: u32, // passes pushing overlapping bodies apart each step
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Block {
+    pub mass: f32,             // added to the mass from the block's material
+    pub velocity: f32,         // initial downwards velocity
+    pub explosive_chance: f32, // of a spawned block being explosive, in classic mode
+    pub preview: u32,          // upcoming blocks shown beside the well
+    pub sleep_threshold: f32,  // kinetic energy under which a resting block stops simulating
+    pub ccd: bool,             // continuous collision detection, so fast blocks can't tunnel
+}
+
+// How something solid behaves when it touches something else
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Material {
+    pub friction: f32,
+    pub restitution: f32, // bounciness, 0 keeps none of the speed of an impact
+    pub density: f32,     // mass per square pixel, 0 for walls
+}
+
+// The material of the walls and each kind of block
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Materials {
+    pub wall: Material,
+    pub normal: Material,
+    pub explosive: Material,
+    pub garbage: Material,
+    pub colored: Material,
+}
+
+// How a block breaks up once it's destroyed
+//  The pieces are blocks of their own that break up again, until they'd be too small.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fracture {
+    pub pieces: u32,   // 0 to vanish, 2 to break in half or 4 to break in quarters
+    pub min_size: f32, // pieces smaller than this on a side vanish instead
+}
+
+// How each kind of block breaks up
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fractures {
+    pub normal: Fracture,
+    pub explosive: Fracture,
+    pub garbage: Fracture,
+    pub colored: Fracture,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Puzzle {
+    pub match_size: u32, // touching blocks of one color needed to clear them
+    pub colors: u32,
+    pub chain_time: f32, // seconds after a clear in which another one counts as a chain
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Volume {
+    pub master: f32,
+    pub sfx: f32,
+    pub music: f32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Controls {
+    pub swap_mouse_buttons: bool,
+    pub pause_on_focus_loss: bool,
+    pub cursor_size: f32,    // width and height of the cursor's hitbox
+    pub drag_hold_time: f32, // seconds a press on a block is held before it becomes a drag
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Player {
+    pub name: String, // shown in the high score table
+}
+
+// Game and engine settings, loaded from settings.toml and the command line
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub camera: Camera,
+    pub window: Window,
+    pub physics: Physics,
+    pub block: Block,
+    pub materials: Materials,
+    pub fracture: Fractures,
+    pub puzzle: Puzzle,
+    pub volume: Volume,
+    pub controls: Controls,
+    pub player: Player,
+}
+
+impl Default for Camera {
+    fn default() -> Camera {
+        Camera {
+            width: 352.,
+            height: 176.,
+        }
+    }
+}
+
+impl Default for Window {
+    fn default() -> Window {
+        Window {
+            scale: 3,
+            vsync: true,
+        }
+    }
+}
+
+impl Default for Physics {
+    fn default() -> Physics {
+        Physics {
+            gravity: 19.8,
+            tick_rate: 60,
+            velocity_iterations: 8,
+            position_iterations: 3,
+        }
+    }
+}
+
+impl Default for Block {
+    fn default() -> Block {
+        Block {
+            mass: 0.,
+            velocity: 32.,
+            explosive_chance: 0.05,
+            preview: 3,
+            sleep_threshold: 0.01,
+            ccd: true,
+        }
+    }
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            friction: 0.8,
+            restitution: 0.,
+            density: 0.001,
+        }
+    }
+}
+
+impl Default for Materials {
+    fn default() -> Materials {
+        let block = Material::default();
+        Materials {
+            wall: Material {
+                density: 0.,
+                ..block
+            },
+            normal: block,
+            explosive: Material {
+                restitution: 0.1,
+                density: 0.0008,
+                ..block
+            },
+            garbage: Material {
+                friction: 1.,
+                density: 0.0015,
+                ..block
+            },
+            colored: block,
+        }
+    }
+}
+
+impl Materials {
+    pub fn get(&self, kind: BlockKind) -> Material {
+        match kind {
+            BlockKind::Normal => self.normal,
+            BlockKind::Explosive => self.explosive,
+            BlockKind::Garbage => self.garbage,
+            BlockKind::Colored(_) => self.colored,
+        }
+    }
+}
+
+impl Default for Fracture {
+    fn default() -> Fracture {
+        Fracture {
+            pieces: 4,
+            min_size: 16.,
+        }
+    }
+}
+
+// explosives blow up rather than break, and puzzle blocks are cleared out of the way
+impl Default for Fractures {
+    fn default() -> Fractures {
+        let vanish = Fracture {
+            pieces: 0,
+            ..Fracture::default()
+        };
+        Fractures {
+            normal: Fracture::default(),
+            explosive: vanish,
+            garbage: Fracture {
+                pieces: 2,
+                min_size: 16.,
+            },
+            colored: vanish,
+        }
+    }
+}
+
+impl Fractures {
+    pub fn get(&self, kind: BlockKind) -> Fracture {
+        match kind {
+            BlockKind::Normal => self.normal,
+            BlockKind::Explosive => self.explosive,
+            BlockKind::Garbage => self.garbage,
+            BlockKind::Colored(_) => self.colored,
+        }
+    }
+}
+
+impl Default for Puzzle {
+    fn default() -> Puzzle {
+        Puzzle {
+            match_size: 3,
+            colors: 4,
+            chain_time: 1.5,
+        }
+    }
+}
+
+impl Default for Volume {
+    fn default() -> Volume {
+        Volume {
+            master: 1.,
+            sfx: 0.8,
+            music: 0.6,
+        }
+    }
+}
+
+impl Default for Controls {
+    fn default() -> Controls {
+        Controls {
+            swap_mouse_buttons: false,
+            pause_on_focus_loss: true,
+            cursor_size: 28.,
+            drag_hold_time: 0.2,
+        }
+    }
+}
+
+impl Default for Player {
+    // the login name if it's printable, otherwise PLAYER
+    fn default() -> Player {
+        let name = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .map(|name| name.to_uppercase())
+            .ok()
+            .filter(|name| valid_name(name))
+            .unwrap_or_else(|| "PLAYER".to_string());
+        Player { name }
+    }
+}
+
+// names must fit the high score table and the font
+fn valid_name(name: &str) -> bool {
+    let len = name.chars().count();
+    len >= 1 && len <= 12 && name.chars().all(|c| c == ' ' || c.is_ascii_graphic())
+}
+
+#[derive(Debug)]
+pub enum SettingsErr {
+    Read(PathBuf, std::io::Error),
+    Write(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Usage(String),
+    Invalid(String),
+}
+
+impl fmt::Display for SettingsErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsErr::Read(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            SettingsErr::Write(path, e) => write!(f, "could not write {:?}: {}", path, e),
+            SettingsErr::Parse(path, e) => write!(f, "{:?}: {}", path, e),
+            SettingsErr::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            SettingsErr::Invalid(msg) => write!(f, "invalid setting: {}", msg),
+        }
+    }
+}
+
+const USAGE: &str =
+    "usage: blockhop [--assets <dir>] [--config <path>] [--set <section.key>=<value>]...
+                [--host <port> | --join <address> | --net-sim <latency_ms>,<loss>]
+                [--broadcast <port>] [--record <path>]
+       blockhop [--assets <dir>] (--watch <address> | --watch-file <path>)
+       blockhop [--assets <dir>] --pack <path>
+
+  --assets <dir>          load textures, shaders and audio from <dir>
+  --pack <path>           pack the asset directory into an archive at <path> and exit
+  --config <path>         read and save settings at <path> instead of blockhop/settings.toml
+                          in the user config directory
+  --set <key>=<value>     override a setting for this run, eg. --set physics.gravity=30
+  --host <port>           offer online versus, waiting for a player to join on <port>
+  --join <address>        offer online versus against the host at <address>, eg. 10.0.0.2:7777
+  --net-sim <ms>,<loss>   offer online versus against the keyboard, over a simulated link
+                          with <ms> of latency each way that drops a <loss> (0-1) of packets
+  --broadcast <port>      stream every frame to viewers connecting on <port>
+  --record <path>         write every frame to a stream file at <path>
+  --watch <address>       show the stream of a game broadcasting at <address> instead of playing
+  --watch-file <path>     play back a stream file instead of playing";
+
+// The parsed command line
+pub struct Args {
+    pub assets: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+    pub pack: Option<PathBuf>,
+    pub overrides: Vec<(String, String)>,
+    pub net: Option<NetConfig>,
+    pub broadcast: Option<u16>,
+    pub record: Option<PathBuf>,
+    pub watch: Option<StreamSource>,
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, SettingsErr> {
+        let mut assets = None;
+        let mut config = None;
+        let mut pack = None;
+        let mut overrides = Vec::new();
+        let mut net = None;
+        let mut broadcast = None;
+        let mut record = None;
+        let mut watch = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--assets" => match args.next() {
+                    Some(dir) => assets = Some(PathBuf::from(dir)),
+                    None => return Err(SettingsErr::Usage("--assets needs a path".to_string())),
+                },
+                "--pack" => match args.next() {
+                    Some(path) => pack = Some(PathBuf::from(path)),
+                    None => return Err(SettingsErr::Usage("--pack needs a path".to_string())),
+                },
+                "--config" => match args.next() {
+                    Some(path) => config = Some(PathBuf::from(path)),
+                    None => return Err(SettingsErr::Usage("--config needs a path".to_string())),
+                },
+                "--set" => {
+                    let set = args.next().unwrap_or_default();
+                    let mut split = set.splitn(2, '=');
+                    match (split.next(), split.next()) {
+                        (Some(key), Some(value)) if !key.is_empty() => {
+                            overrides.push((key.to_string(), value.to_string()))
+                        }
+                        _ => {
+                            return Err(SettingsErr::Usage(format!(
+                                "--set expects <section.key>=<value>, got {:?}",
+                                set
+                            )))
+                        }
+                    }
+                }
+                "--host" => match args.next().and_then(|port| port.parse().ok()) {
+                    Some(port) => net = Some(NetConfig::Host(port)),
+                    None => return Err(SettingsErr::Usage("--host needs a port".to_string())),
+                },
+                "--join" => match args.next() {
+                    Some(address) => net = Some(NetConfig::Join(address)),
+                    None => return Err(SettingsErr::Usage("--join needs an address".to_string())),
+                },
+                "--net-sim" => {
+                    let link = args.next().unwrap_or_default();
+                    let mut split = link.splitn(2, ',');
+                    let latency_ms = split.next().and_then(|ms| ms.parse().ok());
+                    let loss = split.next().and_then(|loss| loss.parse().ok());
+                    match (latency_ms, loss) {
+                        (Some(latency_ms), Some(loss)) if loss >= 0. && loss <= 1. => {
+                            net = Some(NetConfig::Simulated { latency_ms, loss })
+                        }
+                        _ => {
+                            return Err(SettingsErr::Usage(format!(
+                                "--net-sim expects <latency_ms>,<loss>, got {:?}",
+                                link
+                            )))
+                        }
+                    }
+                }
+                "--broadcast" => match args.next().and_then(|port| port.parse().ok()) {
+                    Some(port) => broadcast = Some(port),
+                    None => return Err(SettingsErr::Usage("--broadcast needs a port".to_string())),
+                },
+                "--record" => match args.next() {
+                    Some(path) => record = Some(PathBuf::from(path)),
+                    None => return Err(SettingsErr::Usage("--record needs a path".to_string())),
+                },
+                "--watch" => match args.next() {
+                    Some(address) => watch = Some(StreamSource::Tcp(address)),
+                    None => return Err(SettingsErr::Usage("--watch needs an address".to_string())),
+                },
+                "--watch-file" => match args.next() {
+                    Some(path) => watch = Some(StreamSource::File(PathBuf::from(path))),
+                    None => {
+                        return Err(SettingsErr::Usage("--watch-file needs a path".to_string()))
+                    }
+                },
+                "--help" | "-h" => return Err(SettingsErr::Usage(String::new())),
+                _ => return Err(SettingsErr::Usage(format!("unknown argument {:?}", arg))),
+            }
+        }
+        Ok(Args {
+            assets,
+            config,
+            pack,
+            overrides,
+            net,
+            broadcast,
+            record,
+            watch,
+        })
+    }
+
+    // --config, or blockhop/settings.toml in the platform's user config directory (like
+    // io::get_data_dir, the asset directory is a last resort)
+    pub fn config_path(&self) -> PathBuf {
+        self.config.clone().unwrap_or_else(|| {
+            let dir = dirs::config_dir().map(|dir| dir.join("blockhop"));
+            dir.unwrap_or_else(get_root).join(SETTINGS_FILE)
+        })
+    }
+}
+
+impl Settings {
+    // reads the config file (if it exists), applies the command line overrides and validates
+    pub fn load(args: &Args) -> Result<Settings, SettingsErr> {
+        let config = args.config_path();
+        let mut value = read_config(&config)?;
+        for (key, text) in &args.overrides {
+            let path: Vec<&str> = key.split('.').collect();
+            set_value(&mut value, &path, text).map_err(|part| {
+                SettingsErr::Usage(format!("--set {}: {:?} is not a section", key, part))
+            })?;
+        }
+
+        let settings: Settings = value
+            .try_into()
+            .map_err(|e| SettingsErr::Parse(config, e))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    // the config file alone, without the command line overrides, as it should be saved again
+    pub fn load_saved(path: &Path) -> Result<Settings, SettingsErr> {
+        read_config(path)?
+            .try_into()
+            .map_err(|e| SettingsErr::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SettingsErr> {
+        let text = toml::to_string_pretty(self).expect("settings are always serializable");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| SettingsErr::Write(path.to_path_buf(), e))?;
+        }
+        std::fs::write(path, text).map_err(|e| SettingsErr::Write(path.to_path_buf(), e))
+    }
+
+    fn validate(&self) -> Result<(), SettingsErr> {
+        fn check<T: PartialOrd + fmt::Display>(
+            key: &str,
+            value: T,
+            min: T,
+            max: T,
+        ) -> Result<(), SettingsErr> {
+            if !(value >= min && value <= max) {
+                return Err(SettingsErr::Invalid(format!(
+                    "{} must be between {} and {} (got {})",
+                    key, min, max, value
+                )));
+            }
+            Ok(())
+        }
+
+        check("camera.width", self.camera.width, 64., 4096.)?;
+        check("camera.height", self.camera.height, 64., 4096.)?;
+        check("window.scale", self.window.scale, 1, 6)?;
+        check("physics.gravity", self.physics.gravity, -1000., 1000.)?;
+        check("physics.tick_rate", self.physics.tick_rate, 10, 1000)?;
+        check(
+            "physics.velocity_iterations",
+            self.physics.velocity_iterations,
+            1,
+            100,
+        )?;
+        check(
+            "physics.position_iterations",
+            self.physics.position_iterations,
+            1,
+            100,
+        )?;
+        check("block.mass", self.block.mass, 0., 1000.)?;
+        check("block.velocity", self.block.velocity, -1000., 1000.)?;
+        check(
+            "block.explosive_chance",
+            self.block.explosive_chance,
+            0.,
+            1.,
+        )?;
+        check("block.preview", self.block.preview, 0, 3)?;
+        check(
+            "block.sleep_threshold",
+            self.block.sleep_threshold,
+            0.,
+            100.,
+        )?;
+        let materials = [
+            ("wall", self.materials.wall),
+            ("normal", self.materials.normal),
+            ("explosive", self.materials.explosive),
+            ("garbage", self.materials.garbage),
+            ("colored", self.materials.colored),
+        ];
+        for (name, material) in materials.iter() {
+            let key = |field| format!("materials.{}.{}", name, field);
+            check(&key("friction"), material.friction, 0., 10.)?;
+            check(&key("restitution"), material.restitution, 0., 1.)?;
+            check(&key("density"), material.density, 0., 1.)?;
+        }
+        // a block with no mass at all can't be simulated
+        for (name, material) in materials[1..].iter() {
+            if material.density == 0. && self.block.mass == 0. {
+                return Err(SettingsErr::Invalid(format!(
+                    "materials.{}.density can only be 0 when block.mass isn't",
+                    name
+                )));
+            }
+        }
+        let fractures = [
+            ("normal", self.fracture.normal),
+            ("explosive", self.fracture.explosive),
+            ("garbage", self.fracture.garbage),
+            ("colored", self.fracture.colored),
+        ];
+        for (name, fracture) in fractures.iter() {
+            if ![0, 2, 4].contains(&fracture.pieces) {
+                return Err(SettingsErr::Invalid(format!(
+                    "fracture.{}.pieces must be 0, 2 or 4 (got {})",
+                    name, fracture.pieces
+                )));
+            }
+            let key = format!("fracture.{}.min_size", name);
+            check(&key, fracture.min_size, 2., 32.)?;
+        }
+        check("controls.cursor_size", self.controls.cursor_size, 2., 96.)?;
+        check(
+            "controls.drag_hold_time",
+            self.controls.drag_hold_time,
+            0.,
+            2.,
+        )?;
+        check("puzzle.match_size", self.puzzle.match_size, 2, 8)?;
+        check("puzzle.colors", self.puzzle.colors, 2, 4)?;
+        check("puzzle.chain_time", self.puzzle.chain_time, 0., 10.)?;
+        check("volume.master", self.volume.master, 0., 1.)?;
+        check("volume.sfx", self.volume.sfx, 0., 1.)?;
+        check("volume.music", self.volume.music, 0., 1.)?;
+        if !valid_name(&self.player.name) {
+            return Err(SettingsErr::Invalid(format!(
+                "player.name must be 1 to 12 printable ascii characters (got {:?})",
+                self.player.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+// the config file as a toml table, empty if there isn't one
+fn read_config(path: &Path) -> Result<toml::Value, SettingsErr> {
+    if !path.exists() {
+        return Ok(toml::Value::Table(toml::value::Table::new()));
+    }
+    let text =
+        std::fs::read_to_string(path).map_err(|e| SettingsErr::Read(path.to_path_buf(), e))?;
+    text.parse::<toml::Value>()
+        .map_err(|e| SettingsErr::Parse(path.to_path_buf(), e))
+}
+
+// sets a value at a key path (eg. ["physics", "gravity"]) in a toml table, parsing text as a
+// toml value, returns the part of the path that wasn't a table on failure
+fn set_value(value: &mut toml::Value, path: &[&str], text: &str) -> Result<(), String> {
+    let table = match value {
+        toml::Value::Table(table) => table,
+        _ => return Err(path[0].to_string()),
+    };
+
+    if path.len() > 1 {
+        let child = table
+            .entry(path[0].to_string())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+        return set_value(child, &path[1..], text);
+    }
+
+    // anything that doesn't parse as a toml value is taken as a string
+    let parsed = format!("v = {}", text)
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|mut doc| doc.as_table_mut()?.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(text.to_string()));
+    table.insert(path[0].to_string(), parsed);
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use glutin::EventsLoopProxy;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// Shared between every thread, any of them can ask for the game to stop
+//  The update thread stops first (saving whatever needs saving), which disconnects the
+//  render and audio threads. Requesting also wakes the events loop, which would otherwise
+//  sleep until the next window event.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    proxy: Option<EventsLoopProxy>,
+}
+
+impl Shutdown {
+    pub fn new(proxy: EventsLoopProxy) -> Shutdown {
+        Shutdown {
+            requested: Arc::new(AtomicBool::new(false)),
+            proxy: Some(proxy),
+        }
+    }
+
+    // without an events loop to wake, for tests that never open a window
+    #[cfg(test)]
+    pub fn headless() -> Shutdown {
+        Shutdown {
+            requested: Arc::new(AtomicBool::new(false)),
+            proxy: None,
+        }
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        // fails only if the events loop is already gone
+        if let Some(proxy) = &self.proxy {
+            let _ = proxy.wakeup();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // requests a shutdown when dropped, so a thread that returns early or panics still
+    // brings the others down
+    pub fn guard(&self) -> ShutdownGuard {
+        ShutdownGuard(self.clone())
+    }
+}
+
+pub struct ShutdownGuard(Shutdown);
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.0.request();
+    }
+}
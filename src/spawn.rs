@@ -0,0 +1,184 @@
+use crate::{
+    board::COLUMNS,
+    components::{BlockKind, BlockShape},
+};
+use rand::{seq::SliceRandom, Rng, RngCore};
+use serde_derive::Deserialize;
+use std::collections::VecDeque;
+
+// A block that's going to drop, and the spawn column (0 to 6) it drops in
+#[derive(Copy, Clone, Debug)]
+pub struct Spawn {
+    pub kind: BlockKind,
+    pub shape: BlockShape,
+    pub column: usize, // of the block's first cell
+}
+
+// One step of a scripted sequence, from a level file, and what the other randomizers pick
+//  eg. { column = 3 }, { column = 0, kind = "explosive" } or { column = 6, kind = { colored = 2 } }
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptedSpawn {
+    pub column: usize,
+    pub kind: Option<BlockKind>, // rolled like any other block when left out
+    pub shape: Option<BlockShape>, // same
+}
+
+impl ScriptedSpawn {
+    fn column(column: usize) -> ScriptedSpawn {
+        ScriptedSpawn {
+            column,
+            kind: None,
+            shape: None,
+        }
+    }
+}
+
+// How spawn columns are picked
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RandomizerKind {
+    Uniform,  // any column, every time
+    Bag,      // every column once, in a random order, then again
+    Weighted, // low columns more often than high ones
+    Scripted, // the level's script, over and over
+}
+
+// Picks where (and sometimes what) the next block drops
+//  heights is how many blocks high each column is stacked when the block is queued.
+pub trait Randomizer {
+    fn next(&mut self, rng: &mut dyn RngCore, heights: &[u32; COLUMNS]) -> ScriptedSpawn;
+}
+
+struct Uniform;
+
+impl Randomizer for Uniform {
+    fn next(&mut self, rng: &mut dyn RngCore, _: &[u32; COLUMNS]) -> ScriptedSpawn {
+        ScriptedSpawn::column(rng.gen_range(0, COLUMNS))
+    }
+}
+
+struct Bag(Vec<usize>);
+
+impl Randomizer for Bag {
+    fn next(&mut self, rng: &mut dyn RngCore, _: &[u32; COLUMNS]) -> ScriptedSpawn {
+        if self.0.is_empty() {
+            self.0 = (0..COLUMNS).collect();
+            self.0.shuffle(rng);
+        }
+        ScriptedSpawn::column(self.0.pop().unwrap())
+    }
+}
+
+struct Weighted;
+
+impl Randomizer for Weighted {
+    fn next(&mut self, rng: &mut dyn RngCore, heights: &[u32; COLUMNS]) -> ScriptedSpawn {
+        // a column one block higher is a quarter as likely
+        let weights: Vec<f32> = heights.iter().map(|&h| 0.25f32.powi(h as i32)).collect();
+        let mut pick = rng.gen::<f32>() * weights.iter().sum::<f32>();
+        for (column, weight) in weights.iter().enumerate() {
+            if pick < *weight {
+                return ScriptedSpawn::column(column);
+            }
+            pick -= weight;
+        }
+        ScriptedSpawn::column(COLUMNS - 1)
+    }
+}
+
+struct Scripted {
+    script: Vec<ScriptedSpawn>,
+    next: usize,
+}
+
+impl Randomizer for Scripted {
+    fn next(&mut self, _: &mut dyn RngCore, _: &[u32; COLUMNS]) -> ScriptedSpawn {
+        let spawn = self.script[self.next];
+        self.next = (self.next + 1) % self.script.len();
+        spawn
+    }
+}
+
+// a scripted randomizer with an empty script falls back to uniform, levels are checked for
+// that when they're loaded
+pub fn randomizer(kind: RandomizerKind, script: &[ScriptedSpawn]) -> Box<dyn Randomizer> {
+    match kind {
+        RandomizerKind::Uniform => Box::new(Uniform),
+        RandomizerKind::Bag => Box::new(Bag(Vec::new())),
+        RandomizerKind::Weighted => Box::new(Weighted),
+        RandomizerKind::Scripted if script.is_empty() => Box::new(Uniform),
+        RandomizerKind::Scripted => Box::new(Scripted {
+            script: script.to_vec(),
+            next: 0,
+        }),
+    }
+}
+
+// The next few blocks to drop, decided ahead of time so they can be shown
+pub struct SpawnQueue {
+    upcoming: VecDeque<Spawn>,
+    randomizer: Box<dyn Randomizer>,
+    explosive_chance: f32,
+    colors: Option<u32>, // in puzzle mode every block has one of this many colors
+    shapes: Vec<BlockShape>, // rolled from, all squares when empty
+}
+
+impl SpawnQueue {
+    pub fn new<R: Rng>(
+        length: usize,
+        randomizer: Box<dyn Randomizer>,
+        explosive_chance: f32,
+        colors: Option<u32>,
+        shapes: &[BlockShape],
+        rng: &mut R,
+    ) -> SpawnQueue {
+        let mut queue = SpawnQueue {
+            upcoming: VecDeque::new(),
+            randomizer,
+            explosive_chance,
+            colors,
+            shapes: shapes.to_vec(),
+        };
+        for _ in 0..length.max(1) {
+            let spawn = queue.roll(rng, &[0; COLUMNS]);
+            queue.upcoming.push_back(spawn);
+        }
+        queue
+    }
+
+    // the queued blocks, the next one first
+    pub fn upcoming(&self) -> impl Iterator<Item = &Spawn> {
+        self.upcoming.iter()
+    }
+
+    // takes the next block and queues another
+    pub fn pop<R: Rng>(&mut self, rng: &mut R, heights: &[u32; COLUMNS]) -> Spawn {
+        let spawn = self.roll(rng, heights);
+        self.upcoming.push_back(spawn);
+        self.upcoming.pop_front().unwrap()
+    }
+
+    fn roll<R: Rng>(&mut self, rng: &mut R, heights: &[u32; COLUMNS]) -> Spawn {
+        let spawn = self.randomizer.next(rng, heights);
+        let kind = match (spawn.kind, self.colors) {
+            (Some(kind), _) => kind,
+            (None, Some(colors)) => BlockKind::Colored(rng.gen_range(0, colors) as u8),
+            (None, None) if rng.gen::<f32>() < self.explosive_chance => BlockKind::Explosive,
+            (None, None) => BlockKind::Normal,
+        };
+        let shape = match spawn.shape {
+            Some(shape) => shape,
+            None => *self.shapes.choose(rng).unwrap_or(&BlockShape::Square),
+        };
+
+        // wider blocks are moved over until they fit in the well
+        let (left, right) = shape.reach();
+        let column = nalgebra::clamp(spawn.column, left, COLUMNS - 1 - right);
+        Spawn {
+            kind,
+            shape,
+            column,
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{
+    audio::AudioEvent,
+    render::{RenderState, Snapshots},
+    shutdown::Shutdown,
+    time::get_microseconds_as_u64,
+};
+use bincode::{deserialize, serialize};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use glutin::{Event, WindowEvent};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Spectator stream
+//  "BHST", version (u32 le), then a frame for every tick: its length (u32 le) and the frame,
+//  bincode then deflated. A frame is the render state the window drew for the tick and the
+//  audio events sent during it, so a viewer needs nothing but the assets to show a match.
+const MAGIC: &[u8; 4] = b"BHST";
+const VERSION: u32 = 2;
+const MAX_FRAME_LEN: u32 = 16 << 20;
+
+#[derive(Debug)]
+pub enum StreamErr {
+    Io(String, io::Error), // what was being read or written
+    NotAStream(String),
+    BadFrame(String),
+}
+
+impl fmt::Display for StreamErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamErr::Io(name, e) => write!(f, "{}: {}", name, e),
+            StreamErr::NotAStream(name) => {
+                write!(f, "{} is not a stream, or is from another version", name)
+            }
+            StreamErr::BadFrame(name) => write!(f, "{} has a corrupt frame", name),
+        }
+    }
+}
+
+impl std::error::Error for StreamErr {}
+
+// Where a viewer reads a stream from
+#[derive(Clone, Debug)]
+pub enum StreamSource {
+    Tcp(String), // the address of a game started with --broadcast
+    File(PathBuf),
+}
+
+impl fmt::Display for StreamSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamSource::Tcp(address) => write!(f, "{}", address),
+            StreamSource::File(path) => write!(f, "{:?}", path),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Frame {
+    pub state: RenderState,
+    pub audio: Vec<AudioEvent>, // volume changes are left out, those belong to the viewer
+}
+
+fn header() -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes
+}
+
+// Sends a frame every tick to everyone watching on a TCP port, and/or to a file
+pub struct Broadcaster {
+    listener: Option<TcpListener>,
+    viewers: Vec<TcpStream>,
+    record: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl Broadcaster {
+    pub fn new(port: Option<u16>, record: Option<&Path>) -> Result<Broadcaster, StreamErr> {
+        let listener = match port {
+            Some(port) => {
+                let address = format!("0.0.0.0:{}", port);
+                let listener = TcpListener::bind(&address)
+                    .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                    .map_err(|e| StreamErr::Io(address, e))?;
+                println!("Broadcasting on port {}", port);
+                Some(listener)
+            }
+            None => None,
+        };
+        let record = match record {
+            Some(path) => {
+                let io_err = |e| StreamErr::Io(format!("{:?}", path), e);
+                let mut file = BufWriter::new(File::create(path).map_err(io_err)?);
+                file.write_all(&header()).map_err(io_err)?;
+                Some((path.to_path_buf(), file))
+            }
+            None => None,
+        };
+        Ok(Broadcaster {
+            listener,
+            viewers: Vec::new(),
+            record,
+        })
+    }
+
+    pub fn send(&mut self, frame: &Frame) -> Result<(), StreamErr> {
+        // anyone who connected since the last tick
+        if let Some(listener) = &self.listener {
+            while let Ok((mut viewer, _)) = listener.accept() {
+                let _ = viewer.set_nodelay(true);
+                let ready = viewer
+                    .write_all(&header())
+                    .and_then(|_| viewer.set_nonblocking(true));
+                if ready.is_ok() {
+                    self.viewers.push(viewer);
+                }
+            }
+        }
+
+        let data =
+            serialize(frame).map_err(|_| StreamErr::BadFrame("the broadcast".to_string()))?;
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        let data = encoder
+            .write_all(&data)
+            .and_then(|_| encoder.finish())
+            .map_err(|e| StreamErr::Io("the broadcast".to_string(), e))?;
+        let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&data);
+
+        // a viewer that can't keep up is dropped rather than holding the game up
+        self.viewers
+            .retain(|mut viewer| viewer.write_all(&bytes).is_ok());
+        if let Some((path, file)) = &mut self.record {
+            file.write_all(&bytes)
+                .map_err(|e| StreamErr::Io(format!("{:?}", path), e))?;
+        }
+        Ok(())
+    }
+}
+
+fn open(source: &StreamSource) -> Result<Box<dyn Read + Send>, StreamErr> {
+    let io_err = |e| StreamErr::Io(source.to_string(), e);
+    let mut reader: Box<dyn Read + Send> = match source {
+        StreamSource::Tcp(address) => {
+            Box::new(BufReader::new(TcpStream::connect(address).map_err(io_err)?))
+        }
+        StreamSource::File(path) => Box::new(BufReader::new(File::open(path).map_err(io_err)?)),
+    };
+    let mut bytes = [0u8; 8];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|_| StreamErr::NotAStream(source.to_string()))?;
+    if bytes[..] != header()[..] {
+        return Err(StreamErr::NotAStream(source.to_string()));
+    }
+    Ok(reader)
+}
+
+// the next frame, None at the end of the stream
+fn read_frame(reader: &mut dyn Read, source: &StreamSource) -> Result<Option<Frame>, StreamErr> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(StreamErr::Io(source.to_string(), e)),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(StreamErr::BadFrame(source.to_string()));
+    }
+    // a recording cut off mid frame (the game was killed) just ends there
+    let mut data = vec![0u8; len as usize];
+    match reader.read_exact(&mut data) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(StreamErr::Io(source.to_string(), e)),
+    }
+    let mut frame = Vec::new();
+    DeflateDecoder::new(&data[..])
+        .read_to_end(&mut frame)
+        .map_err(|_| StreamErr::BadFrame(source.to_string()))?;
+    deserialize(&frame)
+        .map(Some)
+        .map_err(|_| StreamErr::BadFrame(source.to_string()))
+}
+
+// Plays a stream in the window instead of running the game, in place of the update thread
+//  Frames are read on a thread of their own, so a quiet connection doesn't hold up closing
+//  the window, and shown at the pace they were made.
+pub fn watch(
+    source: StreamSource,
+    snapshots: Snapshots,
+    input_recv: Receiver<Event>,
+    audio_send: Sender<AudioEvent>,
+    shutdown: Shutdown,
+) -> Result<(), StreamErr> {
+    let mut reader = open(&source)?;
+    let (frame_send, frame_recv) = crossbeam_channel::bounded(60);
+    {
+        let source = source.clone();
+        std::thread::spawn(move || loop {
+            let frame = read_frame(&mut *reader, &source).transpose();
+            match frame {
+                Some(frame) if frame_send.send(frame).is_ok() => {}
+                _ => return,
+            }
+        });
+    }
+
+    // (when the first frame was shown, the time it was made) in microseconds
+    let mut clock = None;
+    let mut ended = false;
+    while !shutdown.is_requested() {
+        for event in input_recv.try_iter() {
+            if let Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } = event
+            {
+                return Ok(());
+            }
+        }
+
+        // after the last frame, the window keeps showing it until it's closed
+        let frame = match frame_recv.recv_timeout(Duration::from_millis(50)) {
+            Ok(frame) => frame?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                if !ended {
+                    println!("End of stream");
+                    ended = true;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+        };
+
+        // move the frame's time onto this process's clock, the renderer interpolates by it
+        let now = get_microseconds_as_u64();
+        let (shown, made) = *clock.get_or_insert((now, frame.state.time));
+        let mut state = frame.state;
+        state.time = shown + state.time.saturating_sub(made);
+        if state.time > now {
+            std::thread::sleep(Duration::from_micros(state.time - now));
+        }
+        for event in frame.audio {
+            let _ = audio_send.send(event);
+        }
+        snapshots.publish(state);
+    }
+    Ok(())
+}
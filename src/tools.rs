@@ -0,0 +1,166 @@
+use crate::input::Input;
+use glutin::VirtualKeyCode;
+use rand::Rng;
+
+// What clicking in the well does
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Hammer,      // 1 damage to the block under the cursor, always available
+    RowClear,    // destroys every block in the cursor's row
+    ColumnClear, // destroys every block in the cursor's column
+    Bomb,        // an explosion at the cursor
+    Freeze,      // blocks stop where they are for a while
+    GravityFlip, // gravity points up for a moment
+}
+
+impl Tool {
+    pub const ALL: [Tool; 6] = [
+        Tool::Hammer,
+        Tool::RowClear,
+        Tool::ColumnClear,
+        Tool::Bomb,
+        Tool::Freeze,
+        Tool::GravityFlip,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Hammer => "HAMMER",
+            Tool::RowClear => "ROW",
+            Tool::ColumnClear => "COLUMN",
+            Tool::Bomb => "BOMB",
+            Tool::Freeze => "FREEZE",
+            Tool::GravityFlip => "FLIP",
+        }
+    }
+
+    // the 16x16 icon drawn in the middle of the cursor, the hammer has none
+    pub fn icon_uv(self) -> Option<(f32, f32)> {
+        match self {
+            Tool::Hammer => None,
+            Tool::RowClear => Some((480., 224.)),
+            Tool::ColumnClear => Some((496., 224.)),
+            Tool::Bomb => Some((512., 224.)),
+            Tool::Freeze => Some((512., 208.)),
+            Tool::GravityFlip => Some((480., 208.)),
+        }
+    }
+
+    // seconds before the tool can be used again
+    fn cooldown(self) -> f32 {
+        match self {
+            Tool::Hammer => 0.,
+            Tool::RowClear | Tool::ColumnClear | Tool::Bomb => 1.,
+            Tool::Freeze => 8.,
+            Tool::GravityFlip => 6.,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// The tools a player holds: charges earned by scoring, the selected tool and cooldowns
+pub struct Toolbelt {
+    selected: Tool,
+    charges: [u32; 6], // the hammer's is ignored
+    cooldowns: [f32; 6],
+    next_award: u32, // score at which the next charge is given
+}
+
+impl Default for Toolbelt {
+    fn default() -> Toolbelt {
+        Toolbelt {
+            selected: Tool::Hammer,
+            charges: [0; 6],
+            cooldowns: [0.; 6],
+            next_award: Toolbelt::AWARD_EVERY,
+        }
+    }
+}
+
+impl Toolbelt {
+    const AWARD_EVERY: u32 = 250;
+
+    pub fn selected(&self) -> Tool {
+        self.selected
+    }
+
+    // None for tools that can be used any number of times
+    pub fn charges(&self, tool: Tool) -> Option<u32> {
+        match tool {
+            Tool::Hammer => None,
+            _ => Some(self.charges[tool.index()]),
+        }
+    }
+
+    fn available(&self, tool: Tool) -> bool {
+        self.charges(tool).map(|n| n > 0).unwrap_or(true)
+    }
+
+    // number keys pick a tool directly, the scroll wheel steps through the ones with charges
+    pub fn select(&mut self, input: &Input) {
+        const KEYS: [VirtualKeyCode; 6] = [
+            VirtualKeyCode::Key1,
+            VirtualKeyCode::Key2,
+            VirtualKeyCode::Key3,
+            VirtualKeyCode::Key4,
+            VirtualKeyCode::Key5,
+            VirtualKeyCode::Key6,
+        ];
+        for (&key, &tool) in KEYS.iter().zip(Tool::ALL.iter()) {
+            if input.key_pressed(key) && self.available(tool) {
+                self.selected = tool;
+            }
+        }
+
+        let count = Tool::ALL.len() as i32;
+        for _ in 0..input.scroll.abs() {
+            let step = input.scroll.signum();
+            let mut i = self.selected.index() as i32;
+            loop {
+                i = (i + step + count) % count;
+                if self.available(Tool::ALL[i as usize]) {
+                    break;
+                }
+            }
+            self.selected = Tool::ALL[i as usize];
+        }
+    }
+
+    pub fn tick(&mut self, dt: f32) {
+        for cooldown in self.cooldowns.iter_mut() {
+            *cooldown = (*cooldown - dt).max(0.);
+        }
+    }
+
+    // takes a charge of the selected tool and returns it, if it's ready
+    //  Once a tool runs out of charges the hammer is selected again.
+    pub fn take(&mut self) -> Option<Tool> {
+        let tool = self.selected;
+        let i = tool.index();
+        if self.cooldowns[i] > 0. || !self.available(tool) {
+            return None;
+        }
+        self.cooldowns[i] = tool.cooldown();
+        if tool != Tool::Hammer {
+            self.charges[i] -= 1;
+            if self.charges[i] == 0 {
+                self.selected = Tool::Hammer;
+            }
+        }
+        Some(tool)
+    }
+
+    // a charge of a random tool for every AWARD_EVERY points scored
+    pub fn award<R: Rng>(&mut self, score: u32, rng: &mut R) -> Option<Tool> {
+        if score < self.next_award {
+            return None;
+        }
+        self.next_award += Self::AWARD_EVERY;
+        let tool = Tool::ALL[rng.gen_range(1, Tool::ALL.len())];
+        self.charges[tool.index()] += 1;
+        Some(tool)
+    }
+}
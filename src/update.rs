@@ -1,426 +1,378 @@
-use crate::{components::*, render::RenderState, time::get_microseconds_as_u64};
-use compy::{compy::*, compy_builder::CompyBuilder, key::Key};
+use crate::{
+    archive::Assets,
+    audio::AudioEvent,
+    game::GameErr,
+    input::Input,
+    level::{Level, LevelErr, DEFAULT_LEVEL},
+    menu::Screens,
+    render::{RenderState, Snapshots},
+    settings::{Args, Settings},
+    shutdown::Shutdown,
+    stream::{Broadcaster, Frame, StreamErr},
+    time::get_microseconds_as_u64,
+};
 use crossbeam_channel::{Receiver, Sender};
 use glutin::{
     ElementState,
     Event::{self, WindowEvent},
-    MouseButton,
+    MouseButton, MouseScrollDelta,
     WindowEvent::*,
 };
-use nalgebra::Vector2;
-use ncollide2d::shape::{Cuboid, ShapeHandle};
-use nphysics2d::{
-    force_generator::DefaultForceGeneratorSet,
-    joint::DefaultJointConstraintSet,
-    math::{Isometry, Point},
-    object::{
-        BodyPartHandle, BodyStatus, ColliderDesc, DefaultBodySet, DefaultColliderSet, RigidBodyDesc,
-    },
-    world::{DefaultGeometricalWorld, DefaultMechanicalWorld},
-};
+use std::{fmt, time::Duration};
+
+// the most time the loop will catch up on after a stall, in microseconds
+const MAX_CATCH_UP: u64 = 250_000;
 
 #[derive(Debug)]
 pub enum UpdateErr {
-    Location(u32, u32),
+    Game(GameErr),
+    Level(LevelErr),
+    Stream(StreamErr),
+}
+
+impl fmt::Display for UpdateErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateErr::Game(e) => write!(f, "game update failed: {}", e),
+            UpdateErr::Level(e) => write!(f, "{}", e),
+            UpdateErr::Stream(e) => write!(f, "stream: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UpdateErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UpdateErr::Game(e) => Some(e),
+            UpdateErr::Level(e) => Some(e),
+            UpdateErr::Stream(e) => Some(e),
+        }
+    }
+}
+
+impl From<GameErr> for UpdateErr {
+    fn from(e: GameErr) -> UpdateErr {
+        UpdateErr::Game(e)
+    }
+}
+
+impl From<LevelErr> for UpdateErr {
+    fn from(e: LevelErr) -> UpdateErr {
+        UpdateErr::Level(e)
+    }
+}
+
+impl From<StreamErr> for UpdateErr {
+    fn from(e: StreamErr) -> UpdateErr {
+        UpdateErr::Stream(e)
+    }
 }
 
 pub fn update(
-    camw: f32,
-    camh: f32,
-    render_send: Sender<RenderState>,
+    settings: Settings,
+    args: Args,
+    snapshots: Snapshots,
     input_recv: Receiver<Event>,
+    audio_send: Sender<AudioEvent>,
+    shutdown: Shutdown,
 ) -> Result<(), UpdateErr> {
-    // world
-    let mut mechanical_world = DefaultMechanicalWorld::new(Vector2::new(0., 19.8));
-    let mut geometrical_world = DefaultGeometricalWorld::<f32>::new();
-    let mut bodies = DefaultBodySet::new();
-    let mut colliders = DefaultColliderSet::new();
-    let mut joint_constraints = DefaultJointConstraintSet::<f32>::new();
-    let mut force_generators = DefaultForceGeneratorSet::<f32>::new();
-
-    // the ecs
-    let mut compy = CompyBuilder::new()
-        .with::<SpriteXY>()
-        .with::<SpriteUV>()
-        .with::<SpriteWH>()
-        .with::<SpriteR>()
-        .with::<PhysicsBody>()
-        .with::<PhysicsCollider>()
-        .with::<SyncSpriteToPhysics>()
-        .with::<CursorSnapSpriteToGrid>()
-        .with::<CursorEmitDestroyEventOnLMBDown>()
-        .with::<SetUVOnLMBDown>()
-        .with::<SetUVOnLMBUp>()
-        .with::<HP>()
-        .with::<TakeCursorDamage>()
-        .with::<KillUpon0HP>()
-        .build();
-    let none_key = Key::default();
-    let sprite_xy_key = compy.get_key_for::<SpriteXY>();
-    let sprite_uv_key = compy.get_key_for::<SpriteUV>();
-    let sprite_wh_key = compy.get_key_for::<SpriteWH>();
-    let sprite_r_key = compy.get_key_for::<SpriteR>();
-    let physics_body_key = compy.get_key_for::<PhysicsBody>();
-    let physics_collider_key = compy.get_key_for::<PhysicsCollider>();
-    let sync_sprite_to_physics_key = compy.get_key_for::<SyncSpriteToPhysics>();
-    let cursor_snap_sprite_to_grid_key = compy.get_key_for::<CursorSnapSpriteToGrid>();
-    let cursor_emit_destroy_event_on_lmb_down_key =
-        compy.get_key_for::<CursorEmitDestroyEventOnLMBDown>();
-    let set_uv_on_lmb_up_key = compy.get_key_for::<SetUVOnLMBUp>();
-    let set_uv_on_lmb_down_key = compy.get_key_for::<SetUVOnLMBDown>();
-    let hp_key = compy.get_key_for::<HP>();
-    let take_cursor_damage_key = compy.get_key_for::<TakeCursorDamage>();
-    let kill_upon_0_hp_key = compy.get_key_for::<KillUpon0HP>();
-
-    // the world is a special permanent handle that is unmoving
-    let world = RigidBodyDesc::new().status(BodyStatus::Static).build();
-    let world = bodies.insert(world);
-    // bottom
-    crate::components::create_wall(
-        (64., camh - 32.),
-        (288., 32.),
-        &compy,
-        world,
-        &mut colliders,
+    // the well every round is played in
+    let assets = Assets::open().map_err(LevelErr::Asset)?;
+    let level = Level::load(&assets, DEFAULT_LEVEL)?;
+
+    // the spectator stream, if one was asked for
+    let mut broadcaster = if args.broadcast.is_some() || args.record.is_some() {
+        Some(Broadcaster::new(
+            args.broadcast,
+            args.record.as_ref().map(|path| path.as_path()),
+        )?)
+    } else {
+        None
+    };
+
+    // the screen stack, starting at the title menu
+    //  Its audio events pass through here on their way out, to go into the stream too.
+    let (tap_send, tap_recv) = crossbeam_channel::unbounded();
+    let screens = Screens::new(
+        settings,
+        args.config_path(),
+        level,
+        args.net.clone(),
+        tap_send,
     );
-    crate::components::create_sprite((0., camh - 80.), (352., 0.), (352., 80.), &compy);
-    crate::components::create_wall(
-        (0., camh - 32. - 48.),
-        (64., 48.),
-        &compy,
-        world,
-        &mut colliders,
-    );
-    // wall seg1
-    let wall_size = 48.;
-    crate::components::create_sprite((0., camh - 80. - 48.), (0., 0.), (352., 48.), &compy);
-    crate::components::create_wall(
-        (0., camh - 32. - 48. - 48.),
-        (64., wall_size),
-        &compy,
-        world,
-        &mut colliders,
-    );
-    crate::components::create_wall(
-        (288., camh - 32. - 48. - 48.),
-        (64., wall_size),
-        &compy,
-        world,
-        &mut colliders,
-    );
-    // wall seg1
-    let wall_size = 48.;
-    crate::components::create_sprite((0., camh - 80. - 48. - 48.), (0., 0.), (352., 48.), &compy);
-    crate::components::create_wall(
-        (0., camh - 32. - 48. - 48. - 48.),
-        (64., wall_size),
-        &compy,
-        world,
-        &mut colliders,
-    );
-    crate::components::create_wall(
-        (288., camh - 32. - 48. - 48. - 48.),
-        (64., wall_size),
-        &compy,
-        world,
-        &mut colliders,
+    run_and_save(
+        screens,
+        &snapshots,
+        input_recv,
+        &tap_recv,
+        &audio_send,
+        broadcaster.as_mut(),
+        &shutdown,
+    )
+}
+
+// runs until the window closes, the player quits or something fails, then saves
+//  Returning sets the shutdown flag (through the thread's guard), which stops the render
+//  thread, and drops the audio sender, which stops the audio thread, so both stop after the
+//  save.
+fn run_and_save(
+    mut screens: Screens,
+    snapshots: &Snapshots,
+    input_recv: Receiver<Event>,
+    audio_tap: &Receiver<AudioEvent>,
+    audio_send: &Sender<AudioEvent>,
+    broadcaster: Option<&mut Broadcaster>,
+    shutdown: &Shutdown,
+) -> Result<(), UpdateErr> {
+    let res = run(
+        &mut screens,
+        snapshots,
+        input_recv,
+        audio_tap,
+        audio_send,
+        broadcaster,
+        shutdown,
     );
-    crate::components::create_cursor(&compy);
-    //crate::components::create_normal_block_particles((128., 0.), &compy, &mut bodies);
+    screens.shutdown();
+    res
+}
+
+fn run(
+    screens: &mut Screens,
+    snapshots: &Snapshots,
+    input_recv: Receiver<Event>,
+    audio_tap: &Receiver<AudioEvent>, // what the screens send, forwarded to audio_send
+    audio_send: &Sender<AudioEvent>,
+    mut broadcaster: Option<&mut Broadcaster>,
+    shutdown: &Shutdown,
+) -> Result<(), UpdateErr> {
+    let settings = screens.settings.clone();
+    let camw = settings.camera.width;
 
     // extra data
-    let mut stat_counter = 0f32;
-    let mut block_drop_counter = 0f32;
-    let mut cursor_x = 0.;
-    let mut cursor_y = 0.;
-    let mut cursor_left_down = false;
-    let mut cursor_last_left_down = false;
+    let mut input = Input::default();
+    let mut window_w = camw as f64 * settings.window.scale as f64;
 
     // game loop
-    //  The inner update loop will simulate the amount of time elapsed since the start
-    //  of the next frame, in whole chunks no larger than sim_time. The frame is only
-    //  pushed to the render task once all the time as been elapsed.
-    let sim_time = 66_666; // 66_666us = 66.666ms = 0.066ms
-    let mut last_update = get_microseconds_as_u64();
-    loop {
-        // calculate how much time needs to be simulated
+    //  Runs fixed length ticks (1/tick_rate seconds) until the simulated time catches up
+    //  with the wall clock, publishing a render state after each one, then sleeps until
+    //  the next tick is due. The renderer never blocks this loop.
+    let tick_time = 1_000_000 / settings.physics.tick_rate as u64; // 16_666us at 60 ticks/s
+    let dt = tick_time as f32 * 0.000001;
+    let mut simulated = get_microseconds_as_u64();
+    while !shutdown.is_requested() {
+        // after a long stall, drop the time rather than running a burst of ticks
         let now = get_microseconds_as_u64();
-        let mut acc = now - last_update;
-        last_update = now;
+        if now.saturating_sub(simulated) > MAX_CATCH_UP {
+            simulated = now - tick_time;
+        }
 
-        // keep running the update process until all time has been simulated
-        while acc > 0 {
-            let time_to_simulate = std::cmp::min(acc, sim_time);
-            acc -= time_to_simulate;
+        // run every tick that's due
+        while simulated + tick_time <= now {
+            simulated += tick_time;
 
             ///////////////////////////////////////////
             // update
-            let dt = time_to_simulate as f32 * 0.000001;
-            let ft_start = get_microseconds_as_u64();
-
-            stat_counter += dt;
-            let print_stats = if stat_counter > 10. {
-                stat_counter -= 10.;
-                true
+            // event poll
+            let controls = screens.settings.controls;
+            let primary = if controls.swap_mouse_buttons {
+                MouseButton::Right
             } else {
-                false
+                MouseButton::Left
             };
-
-            // event poll
-            let mut lmb_pressed = false;
-            let mut lmb_released = false;
             for event in input_recv.try_iter() {
                 match event {
                     WindowEvent {
                         event: CloseRequested,
                         ..
                     } => return Ok(()),
+                    WindowEvent {
+                        event: Resized(size),
+                        ..
+                    } => window_w = size.width,
+                    WindowEvent {
+                        event: Focused(false),
+                        ..
+                    } => {
+                        // releases won't arrive while unfocused
+                        input.focus_lost = true;
+                        input.keys_held.clear();
+                    }
                     WindowEvent {
                         event: CursorMoved { position, .. },
                         ..
                     } => {
-                        cursor_x = position.x as f32;
-                        cursor_y = position.y as f32;
+                        // window coordinates to game coordinates
+                        let scale = window_w / screens.camera().0 as f64;
+                        input.cursor_x = (position.x / scale) as f32;
+                        input.cursor_y = (position.y / scale) as f32;
+                        input.cursor_moved = true;
                     }
                     WindowEvent {
                         event: MouseInput { state, button, .. },
                         ..
-                    } => match (state, button) {
-                        (ElementState::Pressed, MouseButton::Left) => lmb_pressed = true,
-                        (ElementState::Released, MouseButton::Left) => lmb_released = true,
-                        _ => {}
+                    } if button == primary => match state {
+                        ElementState::Pressed => input.lmb_pressed = true,
+                        ElementState::Released => input.lmb_released = true,
+                    },
+                    WindowEvent {
+                        event: MouseWheel { delta, .. },
+                        ..
+                    } => {
+                        let y = match delta {
+                            MouseScrollDelta::LineDelta(_, y) => y as f64,
+                            MouseScrollDelta::PixelDelta(position) => position.y,
+                        };
+                        if y != 0. {
+                            input.scroll -= y.signum() as i32;
+                        }
+                    }
+                    WindowEvent {
+                        event:
+                            KeyboardInput {
+                                input:
+                                    glutin::KeyboardInput {
+                                        state,
+                                        virtual_keycode: Some(key),
+                                        ..
+                                    },
+                                ..
+                            },
+                        ..
+                    } => match state {
+                        ElementState::Pressed => input.press(key),
+                        ElementState::Released => input.release(key),
                     },
                     _ => {}
                 }
             }
 
-            // randomly spawn a normal block every 3 seconds
-            block_drop_counter += dt;
-            if block_drop_counter > 1f32 {
-                block_drop_counter -= 1f32;
-
-                let x = 64 + 16 + (rand::random::<u32>() % 7) * 32;
-                crate::components::create_normal_block(
-                    (x as f32, -16.),
-                    &compy,
-                    &mut bodies,
-                    &mut colliders,
-                );
-
-                compy.update();
-            }
-
-            // update nphysics2d
-            if print_stats {
-                let now = get_microseconds_as_u64();
-                mechanical_world.set_timestep(dt);
-                mechanical_world.step(
-                    &mut geometrical_world,
-                    &mut bodies,
-                    &mut colliders,
-                    &mut joint_constraints,
-                    &mut force_generators,
-                );
-                println!("nphysics update {:?}", get_microseconds_as_u64() - now);
-            } else {
-                mechanical_world.set_timestep(dt);
-                mechanical_world.step(
-                    &mut geometrical_world,
-                    &mut bodies,
-                    &mut colliders,
-                    &mut joint_constraints,
-                    &mut force_generators,
-                );
+            // update the top screen
+            screens.tick(dt, &input)?;
+            input.clear();
+            if screens.should_quit() {
+                return Ok(());
             }
-
-            // if lmb was recently pressed, update on lmb systems
-            if lmb_pressed {
-                // cursor "on press" event
-                let pkey = set_uv_on_lmb_down_key + sprite_uv_key;
-                compy.iterate_mut(
-                    pkey,
-                    none_key,
-                    |lmb_down_uv: &SetUVOnLMBDown, sprite_uv: &mut SpriteUV| {
-                        sprite_uv.0 = lmb_down_uv.0;
-                        sprite_uv.1 = lmb_down_uv.1;
-                        false
-                    },
-                );
-
-                // generate lmb events
-                let mut lmb_events = Vec::new();
-                let pkey = cursor_emit_destroy_event_on_lmb_down_key;
-                compy.iterate_mut(pkey, none_key, || {
-                    lmb_events.push(Point::new(cursor_x / 3., cursor_y / 3.));
-                    println!("mouse event at {}, {}", cursor_x / 3., cursor_y / 3.);
-                    false
-                });
-
-                // handle lmb events
-                if lmb_events.len() > 0 {
-                    let pkey = take_cursor_damage_key + hp_key + physics_collider_key;
-                    compy.iterate_mut(
-                        pkey,
-                        none_key,
-                        |hp: &mut HP, physics_collider: &PhysicsCollider| {
-                            let collider = colliders.get(physics_collider.0).unwrap();
-                            let iso = collider.position();
-                            let shape = collider.shape().downcast_ref::<Cuboid<f32>>().unwrap();
-
-                            for p in &lmb_events {
-                                use crate::ncollide2d::query::PointQuery;
-                                if shape.contains_point(&iso, &p) {
-                                    hp.0 -= 1;
-                                }
-                            }
-
-                            false
-                        },
-                    );
+            let mut audio_events = Vec::new();
+            for event in audio_tap.try_iter() {
+                let _ = audio_send.send(event);
+                match event {
+                    AudioEvent::SetVolume(_) => {}
+                    _ => audio_events.push(event),
                 }
             }
 
-            // if lmb was recently released, update on lmb release systems
-            if lmb_released {
-                let pkey = set_uv_on_lmb_up_key + sprite_uv_key;
-                compy.iterate_mut(
-                    pkey,
-                    none_key,
-                    |lmb_up_uv: &SetUVOnLMBUp, sprite_uv: &mut SpriteUV| {
-                        sprite_uv.0 = lmb_up_uv.0;
-                        sprite_uv.1 = lmb_up_uv.1;
-                        false
-                    },
-                );
-            }
-
-            // map the sprite xy to the cursor position
-            let pkey = cursor_snap_sprite_to_grid_key + sprite_xy_key;
-            // calculate
-            let norm = 80.;
-            let temp_x = ((cursor_x / 3. - norm) / 32.).round() * 32. + norm;
-            let temp_x = nalgebra::clamp(temp_x, norm, 272.);
-            let norm = camh - 32. - 16.;
-            let temp_y = ((cursor_y / 3. - norm) / 32.).round() * 32. + norm;
-            let temp_y = nalgebra::clamp(temp_y, -9999999., norm);
-            let cursor_isom = Isometry::new(Vector2::new(temp_x, temp_y), 0.);
-            compy.iterate_mut(pkey, none_key, |sprite_xy: &mut SpriteXY| {
-                sprite_xy.0 = temp_x - 16.;
-                sprite_xy.1 = temp_y - 16.;
-                false
-            });
-
-            // destroy entities with <0 HP
-            let pkey = hp_key + kill_upon_0_hp_key;
-            compy.iterate_mut(pkey, none_key, |hp: &HP| hp.0 == 0);
-
-            // cleanup phase
-            /*compy.iterate_dead_mut(physics_body_key, none_key, |physics_body: &PhysicsBody| {
-                println!("removing body {:?}", physics_body.0);
-                bodies.remove(physics_body.0);
-            });
-
-            compy.iterate_dead_mut(physics_collider_key, none_key, |physics_collider: &PhysicsCollider| {
-                colliders.remove(physics_collider.0);
-            });*/
-
-            // update ecs
-            compy.update();
-
-            // print stats
-            if print_stats {
-                println!("ft: {:?}", get_microseconds_as_u64() - ft_start);
-                compy.print_stats();
+            ///////////////////////////////////////////
+            // publish the render state for this tick
+            let scale = screens.settings.window.scale as f64;
+            let mut render_state = RenderState::new(scale, screens.camera(), simulated);
+            screens.render(&mut render_state)?;
+            if let Some(broadcaster) = &mut broadcaster {
+                broadcaster.send(&Frame {
+                    state: render_state.clone(),
+                    audio: audio_events,
+                })?;
             }
+            snapshots.publish(render_state);
         }
 
-        ///////////////////////////////////////////
-        // prepare the render state and pass it to the gpu
-        // (this only happens after all time for a frame is simulated (see above))
-
-        // map the sprites position to the physics position
-        let pkey = sprite_xy_key + sprite_r_key + physics_body_key + sync_sprite_to_physics_key;
-        compy.iterate_mut(
-            pkey,
-            none_key,
-            |sprite_xy: &mut SpriteXY, sprite_r: &mut SpriteR, phys: &PhysicsBody| {
-                let pos = bodies.rigid_body(phys.0).unwrap().position();
-                sprite_xy.0 = pos.translation.vector.x;
-                sprite_xy.1 = pos.translation.vector.y;
-                let rot = pos.rotation.into_inner();
-                sprite_r.0 = rot.im.atan2(rot.re);
-                false
-            },
-        );
+        // wait for the next tick
+        let next = simulated + tick_time;
+        let now = get_microseconds_as_u64();
+        if next > now {
+            std::thread::sleep(Duration::from_micros(next - now));
+        }
+    }
+    Ok(())
+}
 
-        // pull some data out of the ECS for the renderer
-        let mut sprite_xys = Vec::new();
-        compy.iterate_mut(sprite_xy_key, none_key, |sprite_xy: &SpriteXY| {
-            sprite_xys.push((sprite_xy.0, sprite_xy.1));
-            false
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullBackend;
+    use glutin::VirtualKeyCode;
+    use std::thread;
+
+    fn press(key: VirtualKeyCode) -> Input {
+        Input {
+            keys_pressed: vec![key],
+            ..Input::default()
+        }
+    }
 
-        let mut sprite_uvs = Vec::new();
-        compy.iterate_mut(sprite_uv_key, none_key, |sprite_uv: &SpriteUV| {
-            sprite_uvs.push((sprite_uv.0, sprite_uv.1));
-            false
-        });
+    // Closing the window with a setting changed, no window or sound device needed
+    //  The update thread has to save before it stops the render thread (which would then
+    //  release its GL resources) and the audio thread, and set the flag once it's done.
+    #[test]
+    fn close_saves_then_stops_every_thread() {
+        let path = std::env::temp_dir().join(format!("blockhop_close_{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let settings = Settings::default();
+        let volume = settings.volume;
+        let shutdown = Shutdown::headless();
+        let snapshots = Snapshots::default();
+        let (input_send, input_recv) = crossbeam_channel::unbounded();
+        let (audio_send, audio_recv) = crossbeam_channel::unbounded();
+
+        let update = {
+            let path = path.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                let _guard = shutdown.guard();
+                let level = toml::from_str(include_str!("../levels/default.toml")).unwrap();
+                let (tap_send, tap_recv) = crossbeam_channel::unbounded();
+                let mut screens = Screens::new(settings, path, level, None, tap_send);
+                // into the options, then the master volume down a notch
+                for &key in &[
+                    VirtualKeyCode::Down,
+                    VirtualKeyCode::Return,
+                    VirtualKeyCode::Left,
+                ] {
+                    screens.tick(0.01, &press(key)).unwrap();
+                }
+                run_and_save(
+                    screens,
+                    &snapshots,
+                    input_recv,
+                    &tap_recv,
+                    &audio_send,
+                    None,
+                    &shutdown,
+                )
+            })
+        };
 
-        let mut sprite_whs = Vec::new();
-        compy.iterate_mut(sprite_wh_key, none_key, |sprite_wh: &SpriteWH| {
-            sprite_whs.push((sprite_wh.0, sprite_wh.1));
-            false
-        });
+        // whether the settings were saved by the time the render thread stopped
+        let render = {
+            let path = path.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                while !shutdown.is_requested() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                path.exists()
+            })
+        };
 
-        let mut sprite_rghs = Vec::new();
-        compy.iterate_mut(sprite_r_key, none_key, |sprite_r: &SpriteR| {
-            sprite_rghs.push((sprite_r.0, sprite_r.1, sprite_r.2));
-            false
-        });
+        let audio =
+            thread::spawn(move || crate::audio::audio(Box::new(NullBackend), volume, audio_recv));
 
-        // generate wirebox data for the renderer
-        let mut wireboxes = Vec::new();
-        compy.iterate_mut(physics_collider_key, none_key, |phys: &PhysicsCollider| {
-            let t = colliders.get(phys.0).unwrap();
-            let xy = t.position().translation.vector;
-            let wh_half = t
-                .shape()
-                .downcast_ref::<Cuboid<f32>>()
-                .unwrap()
-                .half_extents();
-            wireboxes.push((
-                xy.x - wh_half.x,
-                xy.y - wh_half.y,
-                wh_half.x * 2.,
-                wh_half.y * 2.,
-            ));
-            false
-        });
+        let close = Event::WindowEvent {
+            window_id: unsafe { glutin::WindowId::dummy() },
+            event: CloseRequested,
+        };
+        input_send.send(close).unwrap();
 
-        // generate body data for the renderer
-        let mut rigid_bodies = Vec::new();
-        compy.iterate_mut(physics_body_key, none_key, |physics_body: &PhysicsBody| {
-            let pos = bodies
-                .rigid_body(physics_body.0)
-                .unwrap()
-                .position()
-                .translation
-                .vector;
-            rigid_bodies.push((pos.x, pos.y));
-            false
-        });
+        update.join().unwrap().unwrap();
+        assert!(shutdown.is_requested());
+        assert!(
+            render.join().unwrap(),
+            "the render thread stopped before the save"
+        );
+        audio.join().unwrap().unwrap();
 
-        let render_state = RenderState {
-            sprite_xys,
-            sprite_uvs,
-            sprite_whs,
-            sprite_rghs,
-            debug: false,
-            wireboxes: Some(wireboxes),
-            rigid_bodies: Some(rigid_bodies),
-        };
-        render_send
-            .send(render_state)
-            .map_err(|_| UpdateErr::Location(column!(), line!()))?;
+        let saved = Settings::load_saved(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(saved.volume.master < volume.master);
     }
 }